@@ -0,0 +1,1133 @@
+use crate::bus::Bus;
+use crate::state::{StateError, StateReader, StateWriter};
+
+// CPU clock frequency in Hz.
+const CPU_CLOCK: u32 = 4194304;
+// Default output sample rate in Hz.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// Frame sequencer period in clocks (512 Hz).
+const FRAME_SEQUENCER_PERIOD: u16 = 8192;
+
+// Waveforms of the four square duty cycles (12.5%, 25%, 50%, 75%).
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+// Noise channel divisors.
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits which always read as 1 in NR10-NR52.
+const READ_MASK: [u8; 0x17] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+// Length counter.
+struct Length {
+    enabled: bool,
+    counter: u16,
+    // Maximum length (64 or 256)
+    max: u16,
+}
+
+impl Length {
+    fn new(max: u16) -> Self {
+        Length {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    fn load(&mut self, val: u8) {
+        self.counter = self.max - val as u16;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // Clocks the counter. Returns false when the channel has to be disabled.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter > 0;
+        }
+        true
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.counter = r.u16()?;
+        Ok(())
+    }
+}
+
+// Volume envelope.
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, val: u8) {
+        self.initial_volume = val >> 4;
+        self.increase = val & 0x08 > 0;
+        self.period = val & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.initial_volume);
+        w.bool(self.increase);
+        w.u8(self.period);
+        w.u8(self.volume);
+        w.u8(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.initial_volume = r.u8()?;
+        self.increase = r.bool()?;
+        self.period = r.u8()?;
+        self.volume = r.u8()?;
+        self.timer = r.u8()?;
+
+        if self.initial_volume > 15 || self.volume > 15 {
+            return Err(StateError::Invalid("envelope volume out of range"));
+        }
+        Ok(())
+    }
+}
+
+// Frequency sweep of channel 1.
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    // Shadow frequency register
+    shadow: u16,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow: 0,
+        }
+    }
+
+    fn write(&mut self, val: u8) {
+        self.period = (val >> 4) & 0x07;
+        self.negate = val & 0x08 > 0;
+        self.shift = val & 0x07;
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    // Calculates the next frequency. Returns None on overflow.
+    fn calc(&self) -> Option<u16> {
+        let delta = self.shadow >> self.shift;
+        let freq = if self.negate {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        };
+
+        if freq > 2047 {
+            None
+        } else {
+            Some(freq)
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.period);
+        w.bool(self.negate);
+        w.u8(self.shift);
+        w.u8(self.timer);
+        w.bool(self.enabled);
+        w.u16(self.shadow);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.period = r.u8()?;
+        self.negate = r.bool()?;
+        self.shift = r.u8()?;
+        self.timer = r.u8()?;
+        self.enabled = r.bool()?;
+        self.shadow = r.u16()?;
+
+        // A larger shift would overflow in `calc`
+        if self.shift > 7 || self.shadow > 2047 {
+            return Err(StateError::Invalid("sweep out of range"));
+        }
+        Ok(())
+    }
+}
+
+// Square wave channel (channel 1 and 2).
+struct Square {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_pos: u8,
+    freq: u16,
+    // Clocks until next duty step
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+    // Only channel 1 has a sweep unit
+    sweep: Option<Sweep>,
+}
+
+impl Square {
+    fn new(has_sweep: bool) -> Self {
+        Square {
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_pos: 0,
+            freq: 0,
+            timer: 8192,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            sweep: if has_sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 4
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // NRx0: Sweep
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.write(val);
+                }
+            }
+            // NRx1: Duty and length load
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(val & 0x3f);
+            }
+            // NRx2: Volume envelope
+            2 => {
+                self.envelope.write(val);
+                self.dac_enabled = val & 0xf8 > 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            // NRx3: Frequency (lower 8 bits)
+            3 => self.freq = (self.freq & 0x700) | val as u16,
+            // NRx4: Trigger, length enable and frequency (upper 3 bits)
+            4 => {
+                self.freq = (self.freq & 0xff) | ((val as u16 & 0x07) << 8);
+                self.length.enabled = val & 0x40 > 0;
+                if val & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!("Unexpected register: {}", reg),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.freq;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period > 0 || sweep.shift > 0;
+
+            if sweep.shift > 0 && sweep.calc().is_none() {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        match sweep.calc() {
+            Some(freq) if sweep.shift > 0 => {
+                sweep.shadow = freq;
+                self.freq = freq;
+                // Overflow check is done again with the new frequency
+                if sweep.calc().is_none() {
+                    self.enabled = false;
+                }
+            }
+            Some(_) => (),
+            None => self.enabled = false,
+        }
+    }
+
+    fn update(&mut self, tick: u32) {
+        let mut tick = tick;
+
+        // A stopped timer starts a new period rather than expiring forever
+        if self.timer == 0 {
+            self.timer = self.period();
+        }
+        while tick >= self.timer {
+            tick -= self.timer;
+            self.timer = self.period();
+            self.duty_pos = (self.duty_pos + 1) & 0x7;
+        }
+
+        self.timer -= tick;
+    }
+
+    // Returns the digital output (0-15).
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_TABLE[self.duty as usize][self.duty_pos as usize] * self.envelope.volume
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.freq);
+        w.u32(self.timer);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.duty = r.u8()?;
+        self.duty_pos = r.u8()?;
+        self.freq = r.u16()?;
+        self.timer = r.u32()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(r)?;
+        }
+
+        if self.duty > 3 || self.duty_pos > 7 || self.freq > 2047 || self.timer == 0 {
+            return Err(StateError::Invalid("square channel out of range"));
+        }
+        Ok(())
+    }
+}
+
+// Wave channel (channel 3).
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    // Output level (0: mute, 1: 100%, 2: 50%, 3: 25%)
+    volume_code: u8,
+    freq: u16,
+    // Clocks until next sample
+    timer: u32,
+    // Current sample position (0-31)
+    pos: u8,
+    // Wave pattern RAM
+    ram: [u8; 0x10],
+}
+
+impl Wave {
+    fn new() -> Self {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(256),
+            volume_code: 0,
+            freq: 0,
+            timer: 4096,
+            pos: 0,
+            ram: [0; 0x10],
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 2
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // NR30: DAC power
+            0 => {
+                self.dac_enabled = val & 0x80 > 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            // NR31: Length load
+            1 => self.length.load(val),
+            // NR32: Output level
+            2 => self.volume_code = (val >> 5) & 0x03,
+            // NR33: Frequency (lower 8 bits)
+            3 => self.freq = (self.freq & 0x700) | val as u16,
+            // NR34: Trigger, length enable and frequency (upper 3 bits)
+            4 => {
+                self.freq = (self.freq & 0xff) | ((val as u16 & 0x07) << 8);
+                self.length.enabled = val & 0x40 > 0;
+                if val & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!("Unexpected register: {}", reg),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.pos = 0;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn update(&mut self, tick: u32) {
+        let mut tick = tick;
+
+        // A stopped timer starts a new period rather than expiring forever
+        if self.timer == 0 {
+            self.timer = self.period();
+        }
+        while tick >= self.timer {
+            tick -= self.timer;
+            self.timer = self.period();
+            self.pos = (self.pos + 1) & 0x1f;
+        }
+
+        self.timer -= tick;
+    }
+
+    // Returns the digital output (0-15).
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        // Upper nibble is played first
+        let byte = self.ram[(self.pos >> 1) as usize];
+        let sample = if self.pos & 1 == 0 { byte >> 4 } else { byte & 0x0f };
+
+        match self.volume_code {
+            0 => 0,
+            1 => sample,
+            2 => sample >> 1,
+            _ => sample >> 2,
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        self.length.save_state(w);
+        w.u8(self.volume_code);
+        w.u16(self.freq);
+        w.u32(self.timer);
+        w.u8(self.pos);
+        w.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.length.load_state(r)?;
+        self.volume_code = r.u8()?;
+        self.freq = r.u16()?;
+        self.timer = r.u32()?;
+        self.pos = r.u8()?;
+        r.bytes(&mut self.ram)?;
+
+        if self.volume_code > 3 || self.freq > 2047 || self.pos > 0x1f || self.timer == 0 {
+            return Err(StateError::Invalid("wave channel out of range"));
+        }
+        Ok(())
+    }
+}
+
+// Noise channel (channel 4).
+struct Noise {
+    enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    clock_shift: u8,
+    // 7-bit LFSR mode
+    width_mode: bool,
+    divisor_code: u8,
+    // Clocks until next LFSR step
+    timer: u32,
+    // Linear feedback shift register
+    lfsr: u16,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            timer: 8,
+            lfsr: 0x7fff,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // NR41: Length load
+            1 => self.length.load(val & 0x3f),
+            // NR42: Volume envelope
+            2 => {
+                self.envelope.write(val);
+                self.dac_enabled = val & 0xf8 > 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            // NR43: Clock shift, width mode and divisor
+            3 => {
+                self.clock_shift = val >> 4;
+                self.width_mode = val & 0x08 > 0;
+                self.divisor_code = val & 0x07;
+            }
+            // NR44: Trigger and length enable
+            4 => {
+                self.length.enabled = val & 0x40 > 0;
+                if val & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7fff;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn update(&mut self, tick: u32) {
+        let mut tick = tick;
+
+        // A stopped timer starts a new period rather than expiring forever
+        if self.timer == 0 {
+            self.timer = self.period();
+        }
+        while tick >= self.timer {
+            tick -= self.timer;
+            self.timer = self.period();
+
+            let xor = (self.lfsr & 0x1) ^ ((self.lfsr >> 1) & 0x1);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !0x40) | (xor << 6);
+            }
+        }
+
+        self.timer -= tick;
+    }
+
+    // Returns the digital output (0-15).
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x1 > 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.u8(self.clock_shift);
+        w.bool(self.width_mode);
+        w.u8(self.divisor_code);
+        w.u32(self.timer);
+        w.u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.clock_shift = r.u8()?;
+        self.width_mode = r.bool()?;
+        self.divisor_code = r.u8()?;
+        self.timer = r.u32()?;
+        self.lfsr = r.u16()?;
+
+        // Out of range codes would overflow or index past the divisors
+        if self.clock_shift > 15 || self.divisor_code > 7 || self.timer == 0 {
+            return Err(StateError::Invalid("noise channel out of range"));
+        }
+        Ok(())
+    }
+}
+
+// Audio Processing Unit.
+pub struct APU {
+    ch1: Square,
+    ch2: Square,
+    ch3: Wave,
+    ch4: Noise,
+    // Raw register values of NR10-NR51
+    regs: [u8; 0x16],
+    // Sound on/off
+    power: bool,
+    // Elapsed clocks in current frame sequencer step
+    frame_counter: u16,
+    // Frame sequencer step (0-7)
+    frame_step: u8,
+    // Output sample rate in Hz
+    sample_rate: u32,
+    // Elapsed clocks since the last sample, scaled by the sample rate
+    sample_counter: u32,
+    // High-pass filter capacitor charge (left, right)
+    capacitor: (f32, f32),
+    // High-pass filter charge factor per sample
+    charge_factor: f32,
+    // Output samples, interleaved left and right
+    samples: Vec<f32>,
+}
+
+impl APU {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut apu = APU {
+            ch1: Square::new(true),
+            ch2: Square::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            regs: [0; 0x16],
+            power: false,
+            frame_counter: 0,
+            frame_step: 0,
+            sample_rate,
+            sample_counter: 0,
+            capacitor: (0.0, 0.0),
+            charge_factor: 0.0,
+            samples: Vec::new(),
+        };
+        apu.set_sample_rate(sample_rate);
+        apu
+    }
+
+    // Changes the output sample rate.
+    //
+    // This may be called every frame for dynamic rate control, so the
+    // elapsed time towards the next sample is kept.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.charge_factor = 0.999958_f32.powf(CPU_CLOCK as f32 / sample_rate as f32);
+    }
+
+    // Takes the samples produced so far, interleaved left and right.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // Serializes registers and channel state. Output sampling and filtering
+    // carry on across loading a state.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        self.ch1.save_state(w);
+        self.ch2.save_state(w);
+        self.ch3.save_state(w);
+        self.ch4.save_state(w);
+        w.bytes(&self.regs);
+        w.bool(self.power);
+        w.u16(self.frame_counter);
+        w.u8(self.frame_step);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ch1.load_state(r)?;
+        self.ch2.load_state(r)?;
+        self.ch3.load_state(r)?;
+        self.ch4.load_state(r)?;
+        r.bytes(&mut self.regs)?;
+        self.power = r.bool()?;
+        self.frame_counter = r.u16()?;
+        self.frame_step = r.u8()?;
+
+        if self.frame_counter >= FRAME_SEQUENCER_PERIOD || self.frame_step > 7 {
+            return Err(StateError::Invalid("frame sequencer out of range"));
+        }
+        Ok(())
+    }
+
+    // Turns off sound and clears all registers.
+    fn power_off(&mut self) {
+        let wave_ram = self.ch3.ram;
+
+        self.ch1 = Square::new(true);
+        self.ch2 = Square::new(false);
+        self.ch3 = Wave::new();
+        self.ch3.ram = wave_ram;
+        self.ch4 = Noise::new();
+        self.regs = [0; 0x16];
+        self.power = false;
+    }
+
+    // Advances the frame sequencer by one step.
+    fn step_frame_sequencer(&mut self) {
+        // Length counters are clocked at 256 Hz
+        if self.frame_step & 1 == 0 {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+
+        // Sweep is clocked at 128 Hz
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.ch1.clock_sweep();
+        }
+
+        // Envelopes are clocked at 64 Hz
+        if self.frame_step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+
+        self.frame_step = (self.frame_step + 1) & 0x7;
+    }
+
+    // Mixes the channels into a stereo sample.
+    fn mix(&mut self) -> (f32, f32) {
+        let channels = [
+            (self.ch1.dac_enabled, self.ch1.output()),
+            (self.ch2.dac_enabled, self.ch2.output()),
+            (self.ch3.dac_enabled, self.ch3.output()),
+            (self.ch4.dac_enabled, self.ch4.output()),
+        ];
+        let nr50 = self.regs[0x14];
+        let nr51 = self.regs[0x15];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+
+        for (i, &(dac_enabled, output)) in channels.iter().enumerate() {
+            if !dac_enabled {
+                continue;
+            }
+
+            // DAC converts 0-15 to 1.0 to -1.0
+            let analog = 1.0 - (output as f32) / 7.5;
+
+            if nr51 & (0x10 << i) > 0 {
+                left += analog;
+            }
+            if nr51 & (0x01 << i) > 0 {
+                right += analog;
+            }
+        }
+
+        // Master volume (1-8) and channel count
+        left *= (((nr50 >> 4) & 0x7) + 1) as f32 / 32.0;
+        right *= ((nr50 & 0x7) + 1) as f32 / 32.0;
+
+        // Remove DC offset
+        let out_left = left - self.capacitor.0;
+        let out_right = right - self.capacitor.1;
+        self.capacitor.0 = left - out_left * self.charge_factor;
+        self.capacitor.1 = right - out_right * self.charge_factor;
+
+        (out_left, out_right)
+    }
+
+    // Produces output samples for the elapsed clocks.
+    fn update_samples(&mut self, tick: u32) {
+        self.sample_counter += tick * self.sample_rate;
+
+        while self.sample_counter >= CPU_CLOCK {
+            self.sample_counter -= CPU_CLOCK;
+
+            let (left, right) = self.mix();
+
+            // Keep at most one second of audio if nobody drains the buffer
+            if self.samples.len() < (self.sample_rate * 2) as usize {
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+        }
+    }
+}
+
+impl Bus for APU {
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // Wave pattern RAM
+            0xff30..=0xff3f => self.ch3.ram[(addr & 0x0f) as usize] = val,
+            // NR52: Sound on/off
+            0xff26 => {
+                if val & 0x80 == 0 {
+                    self.power_off();
+                } else if !self.power {
+                    self.power = true;
+                    self.frame_step = 0;
+                }
+            }
+            // Registers are read-only while sound is off
+            0xff10..=0xff25 if !self.power => (),
+            0xff10..=0xff25 => {
+                self.regs[(addr - 0xff10) as usize] = val;
+
+                match addr {
+                    0xff10..=0xff14 => self.ch1.write(addr - 0xff10, val),
+                    0xff16..=0xff19 => self.ch2.write(addr - 0xff15, val),
+                    0xff1a..=0xff1e => self.ch3.write(addr - 0xff1a, val),
+                    0xff20..=0xff23 => self.ch4.write(addr - 0xff1f, val),
+                    _ => (),
+                }
+            }
+            0xff27..=0xff2f => (),
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // Wave pattern RAM
+            0xff30..=0xff3f => self.ch3.ram[(addr & 0x0f) as usize],
+            // NR52: Sound on/off and channel status
+            0xff26 => {
+                READ_MASK[0x16]
+                    | (u8::from(self.power) << 7)
+                    | (u8::from(self.ch4.enabled) << 3)
+                    | (u8::from(self.ch3.enabled) << 2)
+                    | (u8::from(self.ch2.enabled) << 1)
+                    | u8::from(self.ch1.enabled)
+            }
+            0xff10..=0xff25 => {
+                let ix = (addr - 0xff10) as usize;
+                self.regs[ix] | READ_MASK[ix]
+            }
+            0xff27..=0xff2f => 0xff,
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn update(&mut self, tick: u8) {
+        let tick = tick as u32;
+
+        if self.power {
+            self.frame_counter += tick as u16;
+            if self.frame_counter >= FRAME_SEQUENCER_PERIOD {
+                self.frame_counter -= FRAME_SEQUENCER_PERIOD;
+                self.step_frame_sequencer();
+            }
+
+            self.ch1.update(tick);
+            self.ch2.update(tick);
+            self.ch3.update(tick);
+            self.ch4.update(tick);
+        }
+
+        self.update_samples(tick);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::reload;
+
+    // Creates an APU with sound on.
+    fn apu() -> APU {
+        let mut apu = APU::new(DEFAULT_SAMPLE_RATE);
+        apu.write(0xff26, 0x80);
+        apu
+    }
+
+    // Runs the APU for the given number of frame sequencer steps.
+    fn step_frame_sequencer(apu: &mut APU, steps: u32) {
+        for _ in 0..steps * FRAME_SEQUENCER_PERIOD as u32 / 128 {
+            apu.update(128);
+        }
+    }
+
+    fn ch1_enabled(apu: &APU) -> bool {
+        apu.read(0xff26) & 0x01 > 0
+    }
+
+    #[test]
+    fn length_counter_disables_channel() {
+        let mut apu = apu();
+        apu.write(0xff12, 0xf0);
+        // Length 2, enabled
+        apu.write(0xff11, 0x3e);
+        apu.write(0xff14, 0xc0);
+        assert!(ch1_enabled(&apu));
+
+        // Clocked on every other step
+        step_frame_sequencer(&mut apu, 2);
+        assert!(ch1_enabled(&apu));
+        step_frame_sequencer(&mut apu, 1);
+        assert!(!ch1_enabled(&apu));
+
+        // Triggering with an expired counter reloads the full length
+        apu.write(0xff14, 0xc0);
+        step_frame_sequencer(&mut apu, 2 * 63);
+        assert!(ch1_enabled(&apu));
+        step_frame_sequencer(&mut apu, 2);
+        assert!(!ch1_enabled(&apu));
+    }
+
+    #[test]
+    fn length_counter_only_counts_when_enabled() {
+        let mut apu = apu();
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff11, 0x3f);
+        apu.write(0xff14, 0x80);
+
+        step_frame_sequencer(&mut apu, 16);
+        assert!(ch1_enabled(&apu));
+    }
+
+    #[test]
+    fn envelope() {
+        let mut apu = apu();
+        // Volume 5, decreasing every envelope clock
+        apu.write(0xff12, 0x51);
+        apu.write(0xff14, 0x80);
+        assert_eq!(apu.ch1.envelope.volume, 5);
+
+        // Clocked once every 8 steps
+        step_frame_sequencer(&mut apu, 7);
+        assert_eq!(apu.ch1.envelope.volume, 5);
+        step_frame_sequencer(&mut apu, 1);
+        assert_eq!(apu.ch1.envelope.volume, 4);
+        step_frame_sequencer(&mut apu, 8 * 10);
+        assert_eq!(apu.ch1.envelope.volume, 0);
+
+        // Volume 14, increasing every 2 envelope clocks up to 15
+        apu.write(0xff17, 0xea);
+        apu.write(0xff19, 0x80);
+        step_frame_sequencer(&mut apu, 8 * 2);
+        assert_eq!(apu.ch2.envelope.volume, 15);
+        step_frame_sequencer(&mut apu, 8 * 4);
+        assert_eq!(apu.ch2.envelope.volume, 15);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel_1() {
+        let mut apu = apu();
+        apu.write(0xff12, 0xf0);
+
+        // Increasing by freq >> 1 overflows on trigger
+        apu.write(0xff10, 0x11);
+        apu.write(0xff13, 0x00);
+        apu.write(0xff14, 0x87);
+        assert!(!ch1_enabled(&apu));
+
+        // 0x500 + 0x280 fits, the check after updating the frequency
+        // overflows
+        apu.write(0xff14, 0x85);
+        assert!(ch1_enabled(&apu));
+        step_frame_sequencer(&mut apu, 3);
+        assert_eq!(apu.ch1.freq, 0x780);
+        assert!(!ch1_enabled(&apu));
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = apu();
+        for addr in 0xff10..=0xff25 {
+            apu.write(addr, 0xff);
+        }
+        apu.write(0xff30, 0x12);
+
+        apu.write(0xff26, 0x00);
+        assert_eq!(apu.read(0xff26), 0x70);
+        for addr in 0xff10..=0xff25 {
+            assert_eq!(apu.read(addr), READ_MASK[(addr - 0xff10) as usize]);
+        }
+        // Registers ignore writes while off, wave RAM is kept
+        apu.write(0xff12, 0xf0);
+        assert_eq!(apu.read(0xff12), 0x00);
+        assert_eq!(apu.read(0xff30), 0x12);
+
+        apu.write(0xff26, 0x80);
+        assert_eq!(apu.read(0xff26), 0xf0);
+        assert_eq!(apu.read(0xff24), 0x00);
+    }
+
+    #[test]
+    fn register_read_masks() {
+        let mut apu = apu();
+        for addr in 0xff10..=0xff25 {
+            apu.write(addr, 0x00);
+        }
+
+        let nr1x: Vec<u8> = (0xff10..=0xff14).map(|addr| apu.read(addr)).collect();
+        assert_eq!(nr1x, [0x80, 0x3f, 0x00, 0xff, 0xbf]);
+        let nr3x: Vec<u8> = (0xff1a..=0xff1e).map(|addr| apu.read(addr)).collect();
+        assert_eq!(nr3x, [0x7f, 0xff, 0x9f, 0xff, 0xbf]);
+        // Unused registers
+        assert_eq!(apu.read(0xff15), 0xff);
+        assert_eq!(apu.read(0xff1f), 0xff);
+        assert_eq!(apu.read(0xff27), 0xff);
+
+        // Written bits read back where readable
+        apu.write(0xff11, 0xc5);
+        assert_eq!(apu.read(0xff11), 0xff);
+        apu.write(0xff11, 0x45);
+        assert_eq!(apu.read(0xff11), 0x7f);
+        apu.write(0xff24, 0x35);
+        assert_eq!(apu.read(0xff24), 0x35);
+    }
+
+    #[test]
+    fn zero_timers_restart() {
+        let mut apu = apu();
+        apu.ch1.timer = 0;
+        apu.ch2.timer = 0;
+        apu.ch3.timer = 0;
+        apu.ch4.timer = 0;
+
+        apu.update(4);
+        assert!(apu.ch1.timer > 0 && apu.ch2.timer > 0);
+        assert!(apu.ch3.timer > 0 && apu.ch4.timer > 0);
+    }
+
+    #[test]
+    fn out_of_range_state_is_rejected() {
+        let corruptions: [fn(&mut APU); 9] = [
+            |apu| apu.ch1.timer = 0,
+            |apu| apu.ch1.freq = 2048,
+            |apu| apu.ch1.sweep.as_mut().unwrap().shift = 16,
+            |apu| apu.ch2.duty_pos = 8,
+            |apu| apu.ch3.timer = 0,
+            |apu| apu.ch3.pos = 0x20,
+            |apu| apu.ch4.clock_shift = 16,
+            |apu| apu.ch4.divisor_code = 8,
+            |apu| apu.frame_step = 8,
+        ];
+
+        let mut loaded = APU::new(DEFAULT_SAMPLE_RATE);
+        reload(|w| apu().save_state(w), |r| loaded.load_state(r)).unwrap();
+
+        for (i, corrupt) in corruptions.iter().enumerate() {
+            let mut apu = apu();
+            corrupt(&mut apu);
+            let result = reload(|w| apu.save_state(w), |r| loaded.load_state(r));
+            assert!(matches!(result, Err(StateError::Invalid(_))), "corruption {}", i);
+        }
+    }
+}
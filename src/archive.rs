@@ -0,0 +1,124 @@
+use std::fs;
+use std::io::{self, Cursor, ErrorKind, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+// Magic numbers of supported archive formats.
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+// Reads a ROM file. Zip and gzip archives are extracted transparently; from
+// a zip archive, the first .gb or .gbc file is used.
+pub fn read_rom(fname: &str) -> io::Result<Vec<u8>> {
+    let data = fs::read(fname)?;
+
+    if data.starts_with(ZIP_MAGIC) {
+        read_zip(data)
+    } else if data.starts_with(GZIP_MAGIC) {
+        let mut rom = Vec::new();
+        GzDecoder::new(&data[..]).read_to_end(&mut rom)?;
+        Ok(rom)
+    } else {
+        Ok(data)
+    }
+}
+
+// Extracts the first ROM from a zip archive.
+fn read_zip(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if !file.is_file() || !is_rom_name(file.name()) {
+            continue;
+        }
+
+        let mut rom = Vec::new();
+        file.read_to_end(&mut rom)?;
+        return Ok(rom);
+    }
+
+    Err(io::Error::new(ErrorKind::NotFound, "no .gb or .gbc file in archive"))
+}
+
+// Returns true if the file name has a ROM extension.
+fn is_rom_name(name: &str) -> bool {
+    match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("gb") || ext.eq_ignore_ascii_case("gbc"),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+    use crate::cartridge::tests::temp_path;
+
+    // Writes a file to the temporary directory and reads it as a ROM.
+    fn read(name: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+        let path = temp_path(name);
+        fs::write(&path, data).unwrap();
+
+        let rom = read_rom(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        rom
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            if name.ends_with('/') {
+                writer.add_directory(*name, FileOptions::default()).unwrap();
+            } else {
+                writer.start_file(*name, FileOptions::default()).unwrap();
+                writer.write_all(data).unwrap();
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn plain_rom() {
+        assert_eq!(read("plain.gb", b"ROM").unwrap(), b"ROM");
+    }
+
+    #[test]
+    fn zip_first_rom() {
+        let data = zip(&[
+            ("README.txt", b"readme"),
+            ("dir.gb/", b""),
+            ("game.GBC", b"first"),
+            ("other.gb", b"second"),
+        ]);
+        assert_eq!(read("first.zip", &data).unwrap(), b"first");
+    }
+
+    #[test]
+    fn zip_without_rom() {
+        let data = zip(&[("README.txt", b"readme"), ("game.gba", b"advance")]);
+        let err = read("no_rom.zip", &data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"compressed").unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(read("game.gb.gz", &data).unwrap(), b"compressed");
+    }
+
+    #[test]
+    fn corrupt_gzip() {
+        assert!(read("corrupt.gb.gz", &[0x1f, 0x8b, 0x08, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::process;
+use std::rc::Rc;
+
+use gbder::cli;
+use gbder::cpu::Registers;
+use gbder::ppu::Renderer;
+use gbder::{Config, GameBoy, CLOCKS_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Exit code when the run passed.
+const EXIT_PASS: i32 = 0;
+// Exit code when the run failed or timed out.
+const EXIT_FAIL: i32 = 1;
+// Exit code on invalid arguments or files.
+const EXIT_ERROR: i32 = 2;
+
+// Frames to run by default (one minute).
+const DEFAULT_FRAMES: u32 = 60 * 60;
+// Opcode of LD B,B, used as a breakpoint by test ROMs.
+const LD_B_B: u8 = 0x40;
+// B, C, D, E, H and L at the LD B,B breakpoint when a Mooneye test passes.
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// Command line options.
+struct Options {
+    rom_fname: String,
+    // Maximum number of frames to run
+    frames: u32,
+    // Stop and pass when the serial output contains this
+    until_serial: Option<String>,
+    // Stop and fail when the serial output contains this
+    fail_serial: Option<String>,
+    // Stop and pass when PC reaches this address
+    until_pc: Option<u16>,
+    // Stop at LD B,B and check the Mooneye result registers
+    until_ld_b_b: bool,
+    // Final frame output
+    png_fname: Option<String>,
+    // Serial output log
+    serial_fname: Option<String>,
+    boot_rom_fname: Option<String>,
+    cgb: Option<bool>,
+    renderer: Renderer,
+}
+
+// Reason the run stopped.
+enum Outcome {
+    Timeout,
+    SerialPassed,
+    SerialFailed,
+    PcReached,
+    Breakpoint(Registers),
+    Lockup,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: gbder-headless [--frames N] [--until-serial TEXT] [--fail-serial TEXT] \
+         [--until-pc ADDR] [--until-ld-b-b] [--png FILE] [--serial-log FILE] \
+         [--mode dmg|cgb] [--renderer scanline|fifo] [--boot-rom FILE] ROM"
+    );
+    process::exit(EXIT_ERROR);
+}
+
+fn parse_args() -> Options {
+    let mut rom_fname = None;
+    let mut frames = DEFAULT_FRAMES;
+    let mut until_serial = None;
+    let mut fail_serial = None;
+    let mut until_pc = None;
+    let mut until_ld_b_b = false;
+    let mut png_fname = None;
+    let mut serial_fname = None;
+    let mut boot_rom_fname = None;
+    let mut cgb = None;
+    let mut renderer = Renderer::Scanline;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+
+        match arg.as_str() {
+            "--frames" => frames = value().parse().unwrap_or_else(|_| usage()),
+            "--until-serial" => until_serial = Some(value()),
+            "--fail-serial" => fail_serial = Some(value()),
+            "--until-pc" => {
+                let addr = value();
+                let addr = addr.trim_start_matches("0x");
+                until_pc = Some(u16::from_str_radix(addr, 16).unwrap_or_else(|_| usage()));
+            }
+            "--until-ld-b-b" => until_ld_b_b = true,
+            "--png" => png_fname = Some(value()),
+            "--serial-log" => serial_fname = Some(value()),
+            "--boot-rom" => boot_rom_fname = Some(value()),
+            "--renderer" => renderer = cli::parse_renderer(&value()).unwrap_or_else(|| usage()),
+            "--mode" => cgb = Some(cli::parse_mode(&value()).unwrap_or_else(|| usage())),
+            _ if arg.starts_with("--") => usage(),
+            _ => rom_fname = Some(arg),
+        }
+    }
+
+    Options {
+        rom_fname: rom_fname.unwrap_or_else(|| usage()),
+        frames,
+        until_serial,
+        fail_serial,
+        until_pc,
+        until_ld_b_b,
+        png_fname,
+        serial_fname,
+        boot_rom_fname,
+        cgb,
+        renderer,
+    }
+}
+
+
+// Runs until a stop condition is met or the frame limit is reached. Returns
+// the outcome and the number of elapsed frames.
+fn run(gameboy: &mut GameBoy, options: &Options, serial: &RefCell<Vec<u8>>) -> (Outcome, u32) {
+    let max_clocks = options.frames as u64 * CLOCKS_PER_FRAME as u64;
+    let mut clocks: u64 = 0;
+    // Serial output already searched for the stop strings
+    let mut serial_checked = 0;
+
+    let outcome = loop {
+        let regs = gameboy.cpu.registers();
+
+        if options.until_pc == Some(regs.pc) {
+            break Outcome::PcReached;
+        }
+        if options.until_ld_b_b && gameboy.cpu.mmu.peek(regs.pc) == LD_B_B {
+            break Outcome::Breakpoint(regs);
+        }
+        if gameboy.lockup().is_some() {
+            break Outcome::Lockup;
+        }
+        if clocks >= max_clocks {
+            break Outcome::Timeout;
+        }
+
+        clocks += gameboy.step() as u64;
+
+        let serial = serial.borrow();
+        if serial.len() != serial_checked {
+            serial_checked = serial.len();
+
+            let output = String::from_utf8_lossy(&serial);
+            if options.fail_serial.as_ref().is_some_and(|text| output.contains(text.as_str())) {
+                break Outcome::SerialFailed;
+            }
+            if options.until_serial.as_ref().is_some_and(|text| output.contains(text.as_str())) {
+                break Outcome::SerialPassed;
+            }
+        }
+    };
+
+    (outcome, (clocks / CLOCKS_PER_FRAME as u64) as u32)
+}
+
+// Writes the frame buffer as a PNG image.
+fn write_png(fname: &str, frame_buffer: &[u8]) -> Result<(), String> {
+    let file = File::create(fname).map_err(|e| e.to_string())?;
+    let (width, height) = (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(frame_buffer).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let options = parse_args();
+    let config = Config {
+        strict: false,
+        boot_rom: cli::load_boot_rom(options.boot_rom_fname.as_deref()).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(EXIT_ERROR);
+        }),
+        cgb: options.cgb,
+        renderer: options.renderer,
+    };
+    let mut gameboy = match GameBoy::load(&options.rom_fname, config) {
+        Ok(gameboy) => gameboy,
+        Err(e) => {
+            eprintln!("Failed to load ROM {}: {}", options.rom_fname, e);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    // Collect everything sent over the serial port
+    let serial = Rc::new(RefCell::new(Vec::new()));
+    let serial_log = serial.clone();
+    gameboy.cpu.mmu.serial.set_callback(Box::new(move |val| serial_log.borrow_mut().push(val)));
+
+    // Battery-backed RAM is not saved, so test runs leave no files behind
+    let (outcome, frames) = run(&mut gameboy, &options, &serial);
+
+    let any_condition = options.until_serial.is_some()
+        || options.fail_serial.is_some()
+        || options.until_pc.is_some()
+        || options.until_ld_b_b;
+    let (passed, reason) = match outcome {
+        Outcome::Timeout if any_condition => (false, "timed out".to_string()),
+        Outcome::Timeout => (true, "frame limit reached".to_string()),
+        Outcome::SerialPassed => (true, "serial output matched".to_string()),
+        Outcome::SerialFailed => (false, "serial output reported failure".to_string()),
+        Outcome::PcReached => (true, format!("PC reached 0x{:04x}", options.until_pc.unwrap_or(0))),
+        Outcome::Breakpoint(regs) => {
+            let result = [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+            (result == MOONEYE_PASS, format!("LD B,B reached with BCDEHL = {:?}", result))
+        }
+        Outcome::Lockup => {
+            let lockup = gameboy.lockup().unwrap();
+            let reason = format!(
+                "CPU locked up: illegal opcode 0x{:02x} at 0x{:04x}",
+                lockup.opcode, lockup.pc
+            );
+            (false, reason)
+        }
+    };
+
+    let mut exit_code = if passed { EXIT_PASS } else { EXIT_FAIL };
+
+    if let Some(fname) = &options.png_fname {
+        if let Err(e) = write_png(fname, gameboy.frame_buffer()) {
+            eprintln!("Failed to write {}: {}", fname, e);
+            exit_code = EXIT_ERROR;
+        }
+    }
+    if let Some(fname) = &options.serial_fname {
+        if let Err(e) = fs::write(fname, &*serial.borrow()) {
+            eprintln!("Failed to write {}: {}", fname, e);
+            exit_code = EXIT_ERROR;
+        }
+    }
+
+    let result = if passed { "PASS" } else { "FAIL" };
+    println!("{}: {} after {} frames", result, reason, frames);
+    process::exit(exit_code);
+}
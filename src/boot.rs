@@ -0,0 +1,76 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+
+// Size of the DMG boot ROM in bytes.
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
+// Size of the CGB boot ROM in bytes.
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+// Error loading a boot ROM.
+#[derive(Debug)]
+pub enum BootRomError {
+    Io(io::Error),
+    // Image is neither a DMG nor a CGB boot ROM
+    BadSize(usize),
+}
+
+impl fmt::Display for BootRomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootRomError::Io(e) => write!(f, "{}", e),
+            BootRomError::BadSize(len) => write!(
+                f,
+                "expected {} or {} bytes, found {}",
+                DMG_BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE, len
+            ),
+        }
+    }
+}
+
+impl error::Error for BootRomError {}
+
+impl From<io::Error> for BootRomError {
+    fn from(e: io::Error) -> Self {
+        BootRomError::Io(e)
+    }
+}
+
+// Reads a DMG or CGB boot ROM image.
+pub fn load(fname: &str) -> Result<Vec<u8>, BootRomError> {
+    let boot_rom = fs::read(fname)?;
+
+    match boot_rom.len() {
+        DMG_BOOT_ROM_SIZE | CGB_BOOT_ROM_SIZE => Ok(boot_rom),
+        len => Err(BootRomError::BadSize(len)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn load_image(len: usize) -> Result<Vec<u8>, BootRomError> {
+        let path = env::temp_dir().join(format!("gbder-test-{}-boot-{}.bin", process::id(), len));
+        fs::write(&path, vec![0; len]).unwrap();
+        let result = load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn loads_dmg_and_cgb_images() {
+        assert_eq!(load_image(DMG_BOOT_ROM_SIZE).unwrap().len(), DMG_BOOT_ROM_SIZE);
+        assert_eq!(load_image(CGB_BOOT_ROM_SIZE).unwrap().len(), CGB_BOOT_ROM_SIZE);
+    }
+
+    #[test]
+    fn rejects_other_sizes() {
+        assert!(matches!(load_image(0x200), Err(BootRomError::BadSize(0x200))));
+        assert!(matches!(load("/nonexistent/boot.bin"), Err(BootRomError::Io(_))));
+    }
+}
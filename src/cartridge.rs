@@ -1,13 +1,71 @@
-use std::fs::File;
-use std::io::{Read};
-// use std::path::PathBuf;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
 
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
 use std::str;
 
+use crate::archive;
 use crate::bus::Bus;
+use crate::mapper::{new_mapper, Mapper, MBC2_RAM_SIZE, RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_32};
+use crate::state::{StateError, StateReader, StateWriter};
+
+// Size of the cartridge header including everything before it.
+const HEADER_END: usize = 0x0150;
+
+// Error loading a cartridge.
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    // ROM is smaller than the cartridge header
+    TooShort(usize),
+    BadChecksum { expected: u8, actual: u8 },
+    // Cartridge type code without a supported mapper
+    UnsupportedMapper(u8),
+    // Unknown ROM size code
+    BadRomSize(u8),
+    // Unknown RAM size code
+    BadRamSize(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "{}", e),
+            CartridgeError::TooShort(len) => {
+                write!(f, "ROM too short for a cartridge header ({} bytes)", len)
+            }
+            CartridgeError::BadChecksum { expected, actual } => write!(
+                f,
+                "ROM header checksum is incorrect (expected 0x{:02x}, found 0x{:02x})",
+                expected, actual
+            ),
+            CartridgeError::UnsupportedMapper(code) => {
+                let cartridge_type: Option<CartridgeType> = FromPrimitive::from_u8(*code);
+                match cartridge_type {
+                    Some(cartridge_type) => {
+                        write!(f, "Unsupported cartridge type: {}", cartridge_type.as_str())
+                    }
+                    None => write!(f, "Unknown cartridge type: 0x{:02x}", code),
+                }
+            }
+            CartridgeError::BadRomSize(code) => write!(f, "Invalid ROM size: 0x{:02x}", code),
+            CartridgeError::BadRamSize(code) => write!(f, "Invalid RAM size: 0x{:02x}", code),
+        }
+    }
+}
+
+impl error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
 
 #[derive(FromPrimitive, Debug)]
 pub enum DestinationCode {
@@ -82,6 +140,24 @@ impl CartridgeType {
             //     _ => "Unknown",
         }
     }
+
+    // Returns true if RAM contents are kept by a battery.
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc1RamBattery
+                | CartridgeType::Mbc2Battery
+                | CartridgeType::RomRamBattery
+                | CartridgeType::Mmm01RamBattery
+                | CartridgeType::Mbc3RamBattery
+                | CartridgeType::Mbc3TimerBattery
+                | CartridgeType::Mbc3TimerRamBattery
+                | CartridgeType::Mbc5RamBattery
+                | CartridgeType::Mbc5RumbleRamBattery
+                | CartridgeType::Mbc7SensorRumbleRamBattery
+                | CartridgeType::HuC1RamBattery
+        )
+    }
 }
 
 pub struct Cartridge {
@@ -91,6 +167,7 @@ pub struct Cartridge {
     pub logo: Vec<u8>,
     pub title: Vec<u8>,
     pub new_licensee_code: Vec<u8>,
+    pub cgb_flag: bool,
     pub sgb_flag: bool,
     pub cartridge_type: CartridgeType,
     pub rom_size: usize,
@@ -99,41 +176,85 @@ pub struct Cartridge {
     pub old_licensee_code: u8,
     pub mask_rom_version_number: u8,
     pub header_checksum: u8,
-    pub rom_banks_amount: u8,
+    pub rom_banks_amount: u16,
     // pub global_checksum: [u8; 2],
-    pub ram_enable: bool,
-    pub bank_no_upper: u8,
-    pub bank_no_lower: u8,
-    pub mode: bool,
+    pub mapper: Box<dyn Mapper>,
+    // Battery save file path
+    save_file: Option<PathBuf>,
+    // RAM has been written since the last flush
+    ram_dirty: bool,
 }
 
 impl Cartridge {
-    pub fn new(fname: &str) -> Self {    
-        let mut rom = Vec::new();
-        let mut file = File::open(fname).unwrap();
-        file.read_to_end(&mut rom).unwrap();
-        
-        Cartridge {            
+    // Loads a cartridge from a ROM file, which may be in a zip or gzip
+    // archive. Battery-backed RAM is kept in a save file next to it.
+    pub fn new(fname: &str, strict: bool) -> Result<Self, CartridgeError> {
+        let rom = archive::read_rom(fname)?;
+        let mut cartridge = Cartridge::from_rom(rom, strict)?;
+
+        if cartridge.cartridge_type.has_battery() {
+            // game.gb.gz is saved to game.sav like game.gb
+            let mut save_file = Path::new(fname).to_path_buf();
+            if save_file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz")) {
+                save_file.set_extension("");
+            }
+            save_file.set_extension("sav");
+            cartridge.read_save_file(&save_file);
+            cartridge.save_file = Some(save_file);
+        }
+
+        Ok(cartridge)
+    }
+
+    // Loads a cartridge from ROM data in memory, without a save file. A header
+    // checksum mismatch is only reported as a warning unless `strict` is set.
+    pub fn from_rom(rom: impl Into<Vec<u8>>, strict: bool) -> Result<Self, CartridgeError> {
+        let rom = rom.into();
+
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooShort(rom.len()));
+        }
+
+        let header_checksum = Cartridge::header_checksum(&rom);
+        if header_checksum != rom[0x014d] {
+            let err = CartridgeError::BadChecksum {
+                expected: rom[0x014d],
+                actual: header_checksum,
+            };
+            if strict {
+                return Err(err);
+            }
+            eprintln!("Warning: {}", err);
+        }
+
+        let cartridge_type = Cartridge::cartridge_type(&rom)?;
+        let rom_size = Cartridge::rom_size(&rom)?;
+        let rom_banks_amount = Cartridge::rom_banks_amount(&rom)?;
+        let mapper = new_mapper(&cartridge_type, rom_banks_amount)
+            .ok_or(CartridgeError::UnsupportedMapper(rom[0x0147]))?;
+        let ram_size = Cartridge::ram_size(&rom)?;
+
+        Ok(Cartridge {
             entry_point: Cartridge::entry_point(&rom),
             logo: Cartridge::logo(&rom),
             title: Cartridge::title(&rom),
             new_licensee_code: Cartridge::new_licensee_code(&rom),
+            cgb_flag: Cartridge::cgb_flag(&rom),
             sgb_flag: Cartridge::sgb_flag(&rom),
-            cartridge_type: Cartridge::cartridge_type(&rom),
-            rom_size: Cartridge::rom_size(&rom),
-            ram_size: Cartridge::ram_size(&rom),
+            cartridge_type,
+            rom_size,
+            ram_size,
             destination_code: Cartridge::destination_code(&rom),
             old_licensee_code: Cartridge::old_licensee_code(&rom),
             mask_rom_version_number: Cartridge::mask_rom_version_number(&rom),
-            header_checksum: Cartridge::header_checksum(&rom),
-            rom_banks_amount: Cartridge::rom_banks_amount(&rom),
-            ram: vec![0; Cartridge::ram_size(&rom)],
-            rom: rom,
-            ram_enable: false,
-            bank_no_upper: 0,
-            bank_no_lower: 0,
-            mode: false,
-        }
+            header_checksum,
+            rom_banks_amount,
+            ram: vec![0; ram_size],
+            rom,
+            mapper,
+            save_file: None,
+            ram_dirty: false,
+        })
     }
 
 
@@ -157,6 +278,9 @@ impl Cartridge {
 
     // 013F-0142 - Manufacturer Code
     // 0143 - CGB Flag
+    fn cgb_flag(rom: &Vec<u8>) -> bool {
+        rom[0x0143] & 0x80 > 0
+    }
 
     // 0144-0145 - New Licensee Code
     fn new_licensee_code(rom: &Vec<u8>) -> Vec<u8> {
@@ -173,12 +297,8 @@ impl Cartridge {
     }
 
     // 0147 - Cartridge Type
-    fn cartridge_type(rom: &Vec<u8>) -> CartridgeType {
-        if let Some(cartridge_type) = FromPrimitive::from_u8(rom[0x0147]) {
-            cartridge_type
-        } else {
-            CartridgeType::RomOnly
-        }
+    fn cartridge_type(rom: &Vec<u8>) -> Result<CartridgeType, CartridgeError> {
+        FromPrimitive::from_u8(rom[0x0147]).ok_or(CartridgeError::UnsupportedMapper(rom[0x0147]))
     }
 
     // 014A - Destination Code
@@ -206,13 +326,13 @@ impl Cartridge {
         for i in 0x0134..0x014d {
             checksum = checksum.wrapping_sub(rom[i]).wrapping_sub(1);
         }
-        if checksum != rom[0x014d] {
-            panic!("ROM header checksum is incorrect");
-        }
         checksum
     }
 
-    // 014E-014F - Global Checksum
+    // 014E-014F - Global Checksum (big endian)
+    pub fn global_checksum(&self) -> u16 {
+        u16::from_be_bytes([self.rom[0x014e], self.rom[0x014f]])
+    }
 
     // 0134-0143 - Title
     fn title(rom: &Vec<u8>) -> Vec<u8> {
@@ -224,19 +344,25 @@ impl Cartridge {
     }
     
     pub fn title_to_string(&self) -> String {
-        String::from_utf8(self.title.to_vec()).unwrap()
+        String::from_utf8_lossy(&self.title).trim_end_matches('\0').to_string()
     }
     
-    // 0148 - ROM Size
-    fn rom_size(rom: &Vec<u8>) -> usize {
-        match rom[0x0148] {
-            0 => 32 * 1024,
-            n => 32 * 1024 << (n as usize),
+    // 0148 - ROM Size, 32KB << n for codes 0x00-0x08
+    fn rom_size(rom: &Vec<u8>) -> Result<usize, CartridgeError> {
+        let code = rom[0x0148];
+        match code {
+            0x00..=0x08 => Ok((32 * 1024) << code),
+            _ => Err(CartridgeError::BadRomSize(code)),
         }
     }
 
-    fn rom_banks_amount(rom: &Vec<u8>) -> u8 {
-        2 << rom[0x0148]
+    // Number of 16KB ROM banks, at least 2
+    fn rom_banks_amount(rom: &Vec<u8>) -> Result<u16, CartridgeError> {
+        let code = rom[0x0148];
+        match code {
+            0x00..=0x08 => Ok(2 << code),
+            _ => Err(CartridgeError::BadRomSize(code)),
+        }
     }
 
     pub fn rom_to_string(&self) -> String {
@@ -244,15 +370,20 @@ impl Cartridge {
     }
 
     // 0149 - RAM Size
-    fn ram_size(rom: &Vec<u8>) -> usize {
+    fn ram_size(rom: &Vec<u8>) -> Result<usize, CartridgeError> {
+        // MBC2 has built-in RAM and reports no external RAM
+        if let Ok(CartridgeType::Mbc2 | CartridgeType::Mbc2Battery) = Cartridge::cartridge_type(rom) {
+            return Ok(MBC2_RAM_SIZE);
+        }
+
         match rom[0x0149] {
-            0 => 0,
-            1 => 2 * 1024,
-            2 => 8 * 1024,
-            3 => 32 * 1024,
-            4 => 128 * 1024,
-            5 => 64 * 1024,
-            _ => panic!("RAM size invalid"),
+            0 => Ok(0),
+            1 => Ok(2 * 1024),
+            2 => Ok(8 * 1024),
+            3 => Ok(32 * 1024),
+            4 => Ok(128 * 1024),
+            5 => Ok(64 * 1024),
+            n => Err(CartridgeError::BadRamSize(n)),
         }
     }
 
@@ -260,35 +391,92 @@ impl Cartridge {
         format!("RAM size {}KB", self.ram_size / 1024)
     }
 
-    fn rom_bank_no(&self) -> u8 {
-        let bank_no = if self.mode {
-            self.bank_no_lower
-        } else {
-            self.bank_no_upper << 5 | self.bank_no_lower
-        };
+    // Loads battery-backed RAM and clock data from a save file.
+    pub fn read_save_file(&mut self, path: &Path) {
+        let mut data = Vec::new();
+        match File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => return,
+            Err(e) => {
+                eprintln!("Failed to read save file {}: {}", path.display(), e);
+                return;
+            }
+        }
+
+        // Save data may be followed by an RTC footer
+        let ram_size = self.ram.len();
+        let footer_size = data.len().wrapping_sub(ram_size);
+        if data.len() < ram_size || !matches!(footer_size, 0 | RTC_FOOTER_SIZE | RTC_FOOTER_SIZE_32) {
+            eprintln!(
+                "Ignoring save file {}: expected {} bytes, found {}",
+                path.display(),
+                ram_size,
+                data.len()
+            );
+            return;
+        }
+
+        if footer_size > 0 {
+            self.mapper.load_rtc(&data[ram_size..]);
+        }
+        self.ram.copy_from_slice(&data[..ram_size]);
+    }
 
-        let bank_no = match bank_no {
-            0 | 0x20 | 0x40 | 0x60 => bank_no + 1,
-            _ => bank_no,
+    // Writes battery-backed RAM and clock data to the save file.
+    pub fn write_save_file(&mut self) {
+        let path = match &self.save_file {
+            Some(path) => path,
+            None => return,
         };
 
-        bank_no & (self.rom_banks_amount - 1)
+        let mut data = self.ram.clone();
+        if let Some(footer) = self.mapper.save_rtc() {
+            data.extend_from_slice(&footer);
+        }
+
+        // Write a temporary file first, so a crash or full disk can't leave
+        // the save file half written
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(&data).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, path));
+        match result {
+            Ok(_) => self.ram_dirty = false,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                eprintln!("Failed to write save file {}: {}", path.display(), e);
+            }
+        }
     }
 
-    fn ram_bank_no(&self) -> u8 {
-        if self.mode {
-            self.bank_no_upper
-        } else {
-            0
+    // Writes the save file if RAM has been modified since the last write.
+    pub fn flush_save_file(&mut self) {
+        if self.ram_dirty {
+            self.write_save_file();
         }
     }
 
-    pub fn read_save_file(&mut self, fname: &str) {
-        if let Ok(mut file) = File::open(fname) {
-            self.ram = Vec::new();
-            file.read_to_end(&mut self.ram).unwrap();
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.blob(&self.mapper.save_state());
+        w.blob(&self.ram);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mapper_state = r.blob()?;
+        let ram = r.blob()?;
+        if mapper_state.len() != self.mapper.save_state().len() || ram.len() != self.ram.len() {
+            return Err(StateError::Invalid("cartridge type or RAM size differs"));
         }
-    }    
+
+        self.mapper.load_state(mapper_state);
+        self.ram.copy_from_slice(ram);
+        // Battery-backed RAM follows the loaded state
+        self.ram_dirty = true;
+        Ok(())
+    }
 
     pub fn debug(&mut self) {
         println!("{}", self.title_to_string());
@@ -307,47 +495,437 @@ impl Cartridge {
 
 impl Bus for Cartridge {
     fn write(&mut self, addr: u16, val: u8) {
-        match addr {
-            // RAM enable
-            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
-            // ROM bank number (lower 5 bits)
-            0x2000..=0x3fff => self.bank_no_lower = val & 0x1f,
-            // RAM bank number or ROM bank number (upper 2 bits)
-            0x4000..=0x5fff => self.bank_no_upper = val & 0x03,
-            // ROM/RAM mode select
-            0x6000..=0x7fff => self.mode = val & 0x01 > 0,
-            // RAM bank 00-03
-            0xa000..=0xbfff => {
-                if !self.ram_enable {
-                    return;
-                }
-                let offset = (8 * 1024) * self.ram_bank_no() as usize;
-                self.ram[(addr & 0x1fff) as usize + offset] = val
-            }
-            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        // Only RAM writes accepted by the mapper need to be saved
+        if self.mapper.write(&mut self.ram, addr, val) {
+            self.ram_dirty = true;
         }
     }
 
     fn read(&self, addr: u16) -> u8 {
-        match addr {
-            // ROM bank 00
-            0x0000..=0x3fff => self.rom[addr as usize],
-            // ROM bank 01-7f
-            0x4000..=0x7fff => {
-                let offset = (16 * 1024) * self.rom_bank_no() as usize;
-                self.rom[(addr & 0x3fff) as usize + offset]
-            }
-            // RAM bank 00-03
-            0xa000..=0xbfff => {
-                if !self.ram_enable {
-                    return 0xff;
-                }
-                let offset = (8 * 1024) * self.ram_bank_no() as usize;
-                self.ram[(addr & 0x1fff) as usize + offset]
-            }
-            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        self.mapper.read(&self.rom, &self.ram, addr)
+    }
+
+    fn update(&mut self, tick: u8) {
+        self.mapper.update(tick)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    // Builds a ROM image with a valid header checksum. Each 16KB bank starts
+    // with its bank number, low byte first.
+    pub(crate) fn build_rom(cartridge_type: u8, rom_code: u8, ram_code: u8) -> Vec<u8> {
+        let banks = 2usize << rom_code;
+        let mut rom = vec![0; banks * 0x4000];
+
+        for bank in 0..banks {
+            rom[bank * 0x4000] = bank as u8;
+            rom[bank * 0x4000 + 1] = (bank >> 8) as u8;
+        }
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x0147] = cartridge_type;
+        rom[0x0148] = rom_code;
+        rom[0x0149] = ram_code;
+        rom[0x014d] = Cartridge::header_checksum(&rom);
+        rom
+    }
+
+    // Returns a file path in the temporary directory unique to this test run.
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("gbder-test-{}-{}", process::id(), name))
+    }
+
+    // Returns the ROM bank mapped at 0x4000-0x7fff.
+    fn switchable_bank(cartridge: &Cartridge) -> u16 {
+        u16::from_le_bytes([cartridge.read(0x4000), cartridge.read(0x4001)])
+    }
+
+    fn load(rom: Vec<u8>) -> Cartridge {
+        Cartridge::from_rom(rom, true).unwrap()
+    }
+
+    #[test]
+    fn ram_dirty_only_on_ram_writes() {
+        // MBC3 with RTC and 8KB RAM
+        let mut cartridge = load(build_rom(0x10, 0x01, 0x02));
+
+        // Disabled RAM
+        cartridge.write(0xa000, 0x12);
+        assert!(!cartridge.ram_dirty);
+
+        // Control registers and RTC registers
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0x4000, 0x08);
+        cartridge.write(0xa000, 0x12);
+        assert!(!cartridge.ram_dirty);
+
+        cartridge.write(0x4000, 0x00);
+        cartridge.write(0xa000, 0x12);
+        assert!(cartridge.ram_dirty);
+
+        // MBC1 without RAM
+        let mut cartridge = load(build_rom(0x01, 0x01, 0x00));
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0xa000, 0x12);
+        assert!(!cartridge.ram_dirty);
+
+        // MBC2 built-in RAM
+        let mut cartridge = load(build_rom(0x06, 0x01, 0x00));
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0xa000, 0x12);
+        assert!(cartridge.ram_dirty);
+    }
+
+    #[test]
+    fn gzip_rom_save_file() {
+        let rom_path = temp_path("gzip.gb.gz");
+        let save_path = temp_path("gzip.sav");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&build_rom(0x03, 0x01, 0x02)).unwrap();
+        fs::write(&rom_path, encoder.finish().unwrap()).unwrap();
+
+        // game.gb.gz is saved to game.sav
+        let mut cartridge = Cartridge::new(rom_path.to_str().unwrap(), true).unwrap();
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0xa000, 0x5a);
+        cartridge.write_save_file();
+
+        let saved = fs::read(&save_path);
+        fs::remove_file(&rom_path).unwrap();
+        fs::remove_file(&save_path).unwrap();
+        assert_eq!(saved.unwrap()[0], 0x5a);
+    }
+
+    #[test]
+    fn mbc1_bank_switching() {
+        // 64 banks
+        let mut cartridge = load(build_rom(0x01, 0x05, 0x00));
+        assert_eq!(switchable_bank(&cartridge), 1);
+
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(switchable_bank(&cartridge), 1);
+        cartridge.write(0x2000, 0x1f);
+        assert_eq!(switchable_bank(&cartridge), 0x1f);
+        // Upper bits select banks 0x20-0x3f, 0x20 is remapped to 0x21
+        cartridge.write(0x4000, 0x01);
+        assert_eq!(switchable_bank(&cartridge), 0x3f);
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(switchable_bank(&cartridge), 0x21);
+        assert_eq!(cartridge.read(0x0000), 0);
+    }
+
+    #[test]
+    fn mbc1_bank_masking() {
+        // 4 banks
+        let mut cartridge = load(build_rom(0x01, 0x01, 0x00));
+
+        cartridge.write(0x2000, 0x05);
+        assert_eq!(switchable_bank(&cartridge), 1);
+        cartridge.write(0x2000, 0x03);
+        assert_eq!(switchable_bank(&cartridge), 3);
+    }
+
+    #[test]
+    fn mbc1_ram_banks() {
+        // 32KB RAM
+        let mut cartridge = load(build_rom(0x03, 0x01, 0x03));
+
+        // Disabled RAM ignores writes
+        cartridge.write(0xa000, 0x12);
+        assert_eq!(cartridge.read(0xa000), 0xff);
+
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0x6000, 0x01);
+        cartridge.write(0x4000, 0x02);
+        cartridge.write(0xa000, 0x34);
+        assert_eq!(cartridge.read(0xa000), 0x34);
+        assert_eq!(cartridge.ram[2 * 0x2000], 0x34);
+
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xa000), 0x00);
+    }
+
+    #[test]
+    fn mbc3_bank_switching() {
+        // 128 banks
+        let mut cartridge = load(build_rom(0x13, 0x06, 0x03));
+        assert_eq!(switchable_bank(&cartridge), 1);
+
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(switchable_bank(&cartridge), 1);
+        cartridge.write(0x2000, 0x7f);
+        assert_eq!(switchable_bank(&cartridge), 0x7f);
+        // Only 7 bits are used
+        cartridge.write(0x2000, 0x85);
+        assert_eq!(switchable_bank(&cartridge), 0x05);
+    }
+
+    #[test]
+    fn mbc3_bank_masking() {
+        // 8 banks
+        let mut cartridge = load(build_rom(0x11, 0x02, 0x00));
+
+        cartridge.write(0x2000, 0x0b);
+        assert_eq!(switchable_bank(&cartridge), 3);
+    }
+
+    #[test]
+    fn mbc5_bank_switching() {
+        // 512 banks
+        let mut cartridge = load(build_rom(0x19, 0x08, 0x00));
+        assert_eq!(switchable_bank(&cartridge), 1);
+
+        // Bank 0 can be mapped
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(switchable_bank(&cartridge), 0);
+        cartridge.write(0x2000, 0x05);
+        cartridge.write(0x3000, 0x01);
+        assert_eq!(switchable_bank(&cartridge), 0x105);
+    }
+
+    #[test]
+    fn mbc5_bank_masking() {
+        // 8 banks
+        let mut cartridge = load(build_rom(0x19, 0x02, 0x00));
+
+        cartridge.write(0x2000, 0x09);
+        assert_eq!(switchable_bank(&cartridge), 1);
+        cartridge.write(0x3000, 0x01);
+        assert_eq!(switchable_bank(&cartridge), 1);
+    }
+
+    #[test]
+    fn mbc2_register_select() {
+        // 16 banks
+        let mut cartridge = load(build_rom(0x06, 0x03, 0x00));
+        assert_eq!(switchable_bank(&cartridge), 1);
+
+        // Address bit 8 set selects the ROM bank, anywhere in 0000-3fff
+        cartridge.write(0x0100, 0x05);
+        assert_eq!(switchable_bank(&cartridge), 5);
+        cartridge.write(0x3fff, 0x0a);
+        assert_eq!(switchable_bank(&cartridge), 10);
+        // Only 4 bits are used
+        cartridge.write(0x2100, 0x13);
+        assert_eq!(switchable_bank(&cartridge), 3);
+
+        // Address bit 8 clear enables RAM and leaves the bank alone
+        assert_eq!(cartridge.read(0xa000), 0xff);
+        cartridge.write(0x3eff, 0x0a);
+        assert_eq!(switchable_bank(&cartridge), 3);
+        cartridge.write(0xa000, 0x05);
+        assert_eq!(cartridge.read(0xa000), 0xf5);
+        cartridge.write(0x0000, 0x00);
+        assert_eq!(cartridge.read(0xa000), 0xff);
+    }
+
+    #[test]
+    fn mbc2_bank_0_maps_bank_1() {
+        let mut cartridge = load(build_rom(0x05, 0x03, 0x00));
+        cartridge.write(0x2100, 0x00);
+        assert_eq!(switchable_bank(&cartridge), 1);
+        cartridge.write(0x2100, 0x10);
+        assert_eq!(switchable_bank(&cartridge), 1);
+    }
+
+    #[test]
+    fn mbc2_ram() {
+        let mut cartridge = load(build_rom(0x06, 0x03, 0x00));
+        assert_eq!(cartridge.ram.len(), MBC2_RAM_SIZE);
+        cartridge.write(0x0000, 0x0a);
+
+        // Only the lower 4 bits are stored, the upper ones read as 1s
+        cartridge.write(0xa000, 0x3c);
+        cartridge.write(0xa1ff, 0xa5);
+        assert_eq!(cartridge.read(0xa000), 0xfc);
+        assert_eq!(cartridge.read(0xa1ff), 0xf5);
+
+        // The 512 half-bytes are echoed up to bfff
+        for addr in [0xa200, 0xa400, 0xb000, 0xbe00] {
+            assert_eq!(cartridge.read(addr), 0xfc, "{:04x}", addr);
+            assert_eq!(cartridge.read(addr + 0x1ff), 0xf5, "{:04x}", addr);
         }
+        cartridge.write(0xbfff, 0x07);
+        assert_eq!(cartridge.read(0xa1ff), 0xf7);
     }
 
-    fn update(&mut self, _tick: u8) {}
-}
\ No newline at end of file
+    // Builds a save file for an MBC3 with 8KB RAM. Registers are S, M, H,
+    // DL, DH followed by the latched ones.
+    fn rtc_save_file(regs: [u8; 10], timestamp: u64, footer_size: usize) -> Vec<u8> {
+        let mut data = vec![0xaa; 0x2000];
+        for reg in regs {
+            data.extend_from_slice(&(reg as u32).to_le_bytes());
+        }
+        match footer_size {
+            RTC_FOOTER_SIZE => data.extend_from_slice(&timestamp.to_le_bytes()),
+            _ => data.extend_from_slice(&(timestamp as u32).to_le_bytes()),
+        }
+        data
+    }
+
+    // Reads an RTC register, latched or latching the current time first.
+    fn read_rtc(cartridge: &mut Cartridge, reg: u8, latch: bool) -> u8 {
+        cartridge.write(0x0000, 0x0a);
+        if latch {
+            cartridge.write(0x6000, 0x00);
+            cartridge.write(0x6000, 0x01);
+        }
+        cartridge.write(0x4000, reg);
+        cartridge.read(0xa000)
+    }
+
+    fn load_save_file(data: &[u8], name: &str) -> Cartridge {
+        let path = temp_path(name);
+        fs::write(&path, data).unwrap();
+
+        let mut cartridge = load(build_rom(0x10, 0x01, 0x02));
+        cartridge.read_save_file(&path);
+        fs::remove_file(&path).unwrap();
+        cartridge
+    }
+
+    fn unix_time() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn rtc_footer_48_bytes() {
+        // Halted, so the clock doesn't advance
+        let regs = [5, 6, 7, 8, 0x41, 1, 2, 3, 4, 0x40];
+        let data = rtc_save_file(regs, unix_time(), RTC_FOOTER_SIZE);
+        assert_eq!(data.len(), 0x2000 + 48);
+        let mut cartridge = load_save_file(&data, "rtc48.sav");
+
+        assert_eq!(cartridge.ram[0], 0xaa);
+        assert_eq!(read_rtc(&mut cartridge, 0x08, false), 1);
+        assert_eq!(read_rtc(&mut cartridge, 0x0b, false), 4);
+        assert_eq!(read_rtc(&mut cartridge, 0x08, true), 5);
+        assert_eq!(read_rtc(&mut cartridge, 0x0a, true), 7);
+        assert_eq!(read_rtc(&mut cartridge, 0x0c, true), 0x41);
+    }
+
+    #[test]
+    fn rtc_footer_44_bytes() {
+        // Saved an hour ago while running
+        let regs = [5, 6, 7, 8, 0, 0, 0, 0, 0, 0];
+        let data = rtc_save_file(regs, unix_time() - 60 * 60, RTC_FOOTER_SIZE_32);
+        assert_eq!(data.len(), 0x2000 + 44);
+        let mut cartridge = load_save_file(&data, "rtc44.sav");
+
+        assert_eq!(cartridge.ram[0], 0xaa);
+        assert_eq!(read_rtc(&mut cartridge, 0x09, true), 6);
+        assert_eq!(read_rtc(&mut cartridge, 0x0a, true), 8);
+        assert_eq!(read_rtc(&mut cartridge, 0x0b, true), 8);
+    }
+
+    #[test]
+    fn save_file_with_bad_size_is_ignored() {
+        let mut cartridge = load_save_file(&[0xaa; 0x2000 + 10], "bad.sav");
+        assert_eq!(cartridge.ram[0], 0x00);
+        assert_eq!(read_rtc(&mut cartridge, 0x0a, true), 0);
+    }
+
+    #[test]
+    fn save_file_round_trip() {
+        let rom_path = temp_path("round_trip.gb");
+        let save_path = rom_path.with_extension("sav");
+        fs::write(&rom_path, build_rom(0x10, 0x01, 0x02)).unwrap();
+
+        let mut cartridge = Cartridge::new(rom_path.to_str().unwrap(), true).unwrap();
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0x4000, 0x00);
+        cartridge.write(0xa000, 0x5a);
+        cartridge.write_save_file();
+        assert_eq!(fs::metadata(&save_path).unwrap().len(), 0x2000 + 48);
+        // The temporary file has replaced the save file
+        assert!(!save_path.with_extension("sav.tmp").exists());
+
+        let cartridge = Cartridge::new(rom_path.to_str().unwrap(), true).unwrap();
+        fs::remove_file(&rom_path).unwrap();
+        fs::remove_file(&save_path).unwrap();
+        assert_eq!(cartridge.ram[0], 0x5a);
+    }
+
+    #[test]
+    fn failed_save_keeps_old_file() {
+        // A directory can't be replaced by the written file
+        let save_path = temp_path("failed.sav");
+        fs::create_dir_all(&save_path).unwrap();
+
+        let mut cartridge = load(build_rom(0x03, 0x01, 0x02));
+        cartridge.save_file = Some(save_path.clone());
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0xa000, 0x5a);
+        cartridge.flush_save_file();
+
+        let tmp_exists = save_path.with_extension("sav.tmp").exists();
+        let old_kept = save_path.is_dir();
+        fs::remove_dir(&save_path).unwrap();
+        assert!(!tmp_exists);
+        assert!(old_kept);
+        assert!(cartridge.ram_dirty);
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = temp_path("missing.gb");
+        let result = Cartridge::new(path.to_str().unwrap(), false);
+        assert!(matches!(result, Err(CartridgeError::Io(_))));
+    }
+
+    #[test]
+    fn too_short() {
+        let result = Cartridge::from_rom(vec![0; 0x100], false);
+        assert!(matches!(result, Err(CartridgeError::TooShort(0x100))));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut rom = build_rom(0x00, 0x00, 0x00);
+        let checksum = rom[0x014d];
+        rom[0x014d] = checksum.wrapping_add(1);
+
+        let result = Cartridge::from_rom(rom.clone(), true);
+        assert!(matches!(
+            result,
+            Err(CartridgeError::BadChecksum { expected, actual })
+                if expected == checksum.wrapping_add(1) && actual == checksum
+        ));
+        // Only a warning unless strict
+        assert!(Cartridge::from_rom(rom, false).is_ok());
+    }
+
+    #[test]
+    fn unsupported_mapper() {
+        for code in [0x20, 0xfc, 0x42] {
+            let result = Cartridge::from_rom(build_rom(code, 0x00, 0x00), false);
+            assert!(matches!(result, Err(CartridgeError::UnsupportedMapper(c)) if c == code));
+        }
+    }
+
+    #[test]
+    fn bad_rom_size() {
+        for code in [0x09, 0x0f, 0x10, 0x52, 0xff] {
+            let mut rom = build_rom(0x01, 0x00, 0x00);
+            rom[0x0148] = code;
+            let result = Cartridge::from_rom(rom, false);
+            assert!(matches!(result, Err(CartridgeError::BadRomSize(c)) if c == code));
+        }
+    }
+
+    #[test]
+    fn bad_ram_size() {
+        let result = Cartridge::from_rom(build_rom(0x03, 0x00, 0x06), false);
+        assert!(matches!(result, Err(CartridgeError::BadRamSize(0x06))));
+    }
+}
@@ -0,0 +1,56 @@
+use crate::boot;
+use crate::ppu::Renderer;
+
+// Parses the value of --mode into a CGB mode override.
+pub fn parse_mode(value: &str) -> Option<bool> {
+    match value {
+        "dmg" => Some(false),
+        "cgb" => Some(true),
+        _ => None,
+    }
+}
+
+// Parses the value of --renderer.
+pub fn parse_renderer(value: &str) -> Option<Renderer> {
+    match value {
+        "scanline" => Some(Renderer::Scanline),
+        "fifo" => Some(Renderer::Fifo),
+        _ => None,
+    }
+}
+
+// Loads the boot ROM given with --boot-rom, if any. The error is the message
+// to show.
+pub fn load_boot_rom(fname: Option<&str>) -> Result<Option<Vec<u8>>, String> {
+    let fname = match fname {
+        Some(fname) => fname,
+        None => return Ok(None),
+    };
+
+    boot::load(fname)
+        .map(Some)
+        .map_err(|e| format!("Failed to load boot ROM {}: {}", fname, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_and_renderer() {
+        assert_eq!(parse_mode("dmg"), Some(false));
+        assert_eq!(parse_mode("cgb"), Some(true));
+        assert_eq!(parse_mode("gba"), None);
+        assert_eq!(parse_renderer("scanline"), Some(Renderer::Scanline));
+        assert_eq!(parse_renderer("fifo"), Some(Renderer::Fifo));
+        assert_eq!(parse_renderer("FIFO"), None);
+    }
+
+    #[test]
+    fn boot_rom() {
+        assert_eq!(load_boot_rom(None), Ok(None));
+
+        let err = load_boot_rom(Some("/nonexistent/boot.bin")).unwrap_err();
+        assert!(err.starts_with("Failed to load boot ROM /nonexistent/boot.bin: "), "{}", err);
+    }
+}
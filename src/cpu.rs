@@ -1,4 +1,31 @@
+use crate::cartridge::{Cartridge, CartridgeError};
 use crate::mmu::MMU;
+use crate::ppu::Renderer;
+use crate::state::{Sections, StateError, StateWriter};
+
+// CPU lock-up caused by an illegal opcode.
+#[derive(Clone, Copy, Debug)]
+pub struct Lockup {
+    // Address of the illegal opcode
+    pub pc: u16,
+    pub opcode: u8,
+}
+
+// Snapshot of the CPU registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+}
 
 pub struct CPU {
     pub mmu: MMU,
@@ -13,15 +40,49 @@ pub struct CPU {
     h: u8,
     l: u8,
     ime: bool, // IME - 割り込み有効フラグ (Interrupt Master Enable Flag)
+    // Instructions until IME is set by EI
+    ime_delay: u8,
     cycle: u8,
     halted: bool,
+    // HALT bug: next opcode byte is read twice
+    halt_bug: bool,
+    // Low-power mode entered by STOP
+    stopped: bool,
+    // Set once an illegal opcode has been executed
+    lockup: Option<Lockup>,
 }
 
 impl CPU {
-    pub fn new(rom_name: &str) -> Self {
-        CPU {
-            mmu: MMU::new(rom_name),
-            pc: 0x100,
+    // Creates a CPU. Without a boot ROM, execution starts at the cartridge
+    // entry point with the register values left by the boot ROM. CGB mode is
+    // selected from the cartridge header unless overridden.
+    pub fn new(
+        rom_name: &str,
+        strict: bool,
+        boot_rom: Option<Vec<u8>>,
+        cgb: Option<bool>,
+        renderer: Renderer,
+    ) -> Result<Self, CartridgeError> {
+        let mmu = MMU::new(rom_name, strict, boot_rom, cgb, renderer)?;
+
+        Ok(CPU::with_mmu(mmu))
+    }
+
+    // Creates a CPU with a loaded cartridge, such as one from ROM data in
+    // memory.
+    pub fn with_cartridge(
+        cartridge: Cartridge,
+        boot_rom: Option<Vec<u8>>,
+        cgb: Option<bool>,
+        renderer: Renderer,
+    ) -> Self {
+        CPU::with_mmu(MMU::with_cartridge(cartridge, boot_rom, cgb, renderer))
+    }
+
+    fn with_mmu(mmu: MMU) -> Self {
+        let mut cpu = CPU {
+            mmu,
+            pc: 0,
             sp: 0,
             a: 0,
             f: 0,
@@ -32,9 +93,43 @@ impl CPU {
             h: 0,
             l: 0,
             ime: false,
+            ime_delay: 0,
             cycle: 0,
             halted: false,
+            halt_bug: false,
+            stopped: false,
+            lockup: None,
+        };
+
+        if !cpu.mmu.boot_rom_mapped() {
+            cpu.init_post_boot();
+        }
+
+        cpu
+    }
+
+    // Initializes registers to the values left by the boot ROM.
+    fn init_post_boot(&mut self) {
+        if self.mmu.cgb() {
+            // A = 0x11 identifies CGB hardware
+            self.set_af(0x1180);
+            self.set_bc(0x0000);
+            self.set_de(0xff56);
+            self.set_hl(0x000d);
+            self.sp = 0xfffe;
+            self.pc = 0x0100;
+            return;
         }
+
+        // H and C flags depend on the header checksum
+        let f = if self.mmu.cartridge.header_checksum == 0 { 0x80 } else { 0xb0 };
+
+        self.set_af(0x0100 | f);
+        self.set_bc(0x0013);
+        self.set_de(0x00d8);
+        self.set_hl(0x014d);
+        self.sp = 0xfffe;
+        self.pc = 0x0100;
     }
 
     // AF register
@@ -113,27 +208,140 @@ impl CPU {
         (self.f >> 4) & 1 == 1
     }
 
+    // Returns the current register values.
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            f: self.f,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+            ime: self.ime,
+        }
+    }
+
+    // Sets the register values. The lower 4 bits of F always read as 0.
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.a = regs.a;
+        self.f = regs.f & 0xf0;
+        self.b = regs.b;
+        self.c = regs.c;
+        self.d = regs.d;
+        self.e = regs.e;
+        self.h = regs.h;
+        self.l = regs.l;
+        self.sp = regs.sp;
+        self.pc = regs.pc;
+        self.ime = regs.ime;
+    }
+
+    // Returns the lock-up state, if an illegal opcode has been executed.
+    pub fn lockup(&self) -> Option<Lockup> {
+        self.lockup
+    }
+
+    // Serializes CPU state followed by the sections of all other components.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.section(b"CPU ", |w| {
+            w.u16(self.af());
+            w.u16(self.bc());
+            w.u16(self.de());
+            w.u16(self.hl());
+            w.u16(self.sp);
+            w.u16(self.pc);
+            w.bool(self.ime);
+            w.u8(self.ime_delay);
+            w.bool(self.halted);
+            w.bool(self.halt_bug);
+            w.bool(self.stopped);
+            w.bool(self.lockup.is_some());
+            if let Some(lockup) = self.lockup {
+                w.u16(lockup.pc);
+                w.u8(lockup.opcode);
+            }
+        });
+
+        self.mmu.save_state(w);
+    }
+
+    pub(crate) fn load_state(&mut self, sections: &Sections) -> Result<(), StateError> {
+        let mut r = sections.reader(b"CPU ")?;
+
+        self.set_af(r.u16()?);
+        self.set_bc(r.u16()?);
+        self.set_de(r.u16()?);
+        self.set_hl(r.u16()?);
+        self.sp = r.u16()?;
+        self.pc = r.u16()?;
+        self.ime = r.bool()?;
+        self.ime_delay = r.u8()?;
+        self.halted = r.bool()?;
+        self.halt_bug = r.bool()?;
+        self.stopped = r.bool()?;
+        self.lockup = if r.bool()? {
+            Some(Lockup {
+                pc: r.u16()?,
+                opcode: r.u8()?,
+            })
+        } else {
+            None
+        };
+
+        self.mmu.load_state(sections)
+    }
+
     pub fn step(&mut self) -> u8 {
-        let mut total_cycle = 0;
         self.cycle = 0;
 
-        if self.halted {
+        // Nothing is executed anymore after a lock-up or until a button is
+        // pressed after STOP
+        if self.stopped && self.mmu.joypad.any_pressed() {
+            self.stopped = false;
+        }
+        if self.stopped || self.lockup.is_some() {
             self.cycle += 4;
-        } else {
-            self.fetch_and_exec();
+            self.mmu.update(self.cycle);
+            return self.cycle;
+        }
+
+        // HALT is exited on any pending interrupt, even if IME is not set
+        if self.halted {
+            if self.pending_irqs() == 0 {
+                self.cycle += 4;
+            } else {
+                self.halted = false;
+            }
         }
 
-        total_cycle += self.cycle;
+        if !self.halted {
+            if self.ime && self.pending_irqs() != 0 {
+                self.check_irqs();
+            } else {
+                self.fetch_and_exec();
+
+                // EI takes effect after the following instruction
+                if self.ime_delay > 0 {
+                    self.ime_delay -= 1;
+                    if self.ime_delay == 0 {
+                        self.ime = true;
+                    }
+                }
+            }
+        }
 
         self.mmu.update(self.cycle);
 
-        if self.ime {
-            self.cycle = 0;
-            self.check_irqs();
-            self.mmu.update(self.cycle);
-            total_cycle += self.cycle;
-        }        
-        total_cycle
+        // Return elapsed time in normal speed clocks
+        if self.mmu.double_speed() {
+            self.cycle / 2
+        } else {
+            self.cycle
+        }
     }
 
     // 8-bit operand
@@ -194,8 +402,9 @@ impl CPU {
 
     // 8-bit immediate memory
     fn read_d8(&mut self) -> u8 {
-        let pc = self.pc;
-        let imm = self.read_mem8(pc);
+        self.mmu.sync_dma(self.cycle);
+        let imm = self.mmu.fetch(self.pc);
+        self.cycle += 4;
         self.pc = self.pc.wrapping_add(1);
 
         imm
@@ -203,11 +412,10 @@ impl CPU {
 
     // 16-bit immediate memory
     fn read_d16(&mut self) -> u16 {
-        let pc = self.pc;
-        let imm = self.read_mem16(pc);
-        self.pc = self.pc.wrapping_add(2);
+        let lo = self.read_d8();
+        let hi = self.read_d8();
 
-        imm
+        (hi as u16) << 8 | lo as u16
     }
 
     // Checks branch condition
@@ -223,11 +431,13 @@ impl CPU {
 
     // 8-bit value memory
     fn write_mem8(&mut self, addr: u16, val: u8) {
+        self.mmu.sync_dma(self.cycle);
         self.mmu.write(addr, val);
         self.cycle += 4;
     }
 
     fn read_mem8(&mut self, addr: u16) -> u8 {
+        self.mmu.sync_dma(self.cycle);
         let ret = self.mmu.read(addr);
         self.cycle += 4;
         ret
@@ -1034,15 +1244,20 @@ impl CPU {
     // Disable interrupt
     fn di(&mut self) {
         self.ime = false;
+        self.ime_delay = 0;
     }
 
     // Enable interrupt
     fn ei(&mut self) {
-        self.ime = true;
+        // Counted down at the end of this and the next instruction
+        if !self.ime {
+            self.ime_delay = 2;
+        }
     }
 
     // Enable interrupt and return
     fn reti(&mut self) {
+        // Unlike EI, RETI enables interrupts immediately
         self.ime = true;
         self._ret();
     }
@@ -1068,44 +1283,59 @@ impl CPU {
         }
     }
 
-        // Checks IRQs and execute ISRs if requested.
-        fn check_irqs(&mut self) {
-            // Bit 0 has the highest priority
-            for i in 0..5 {
-                let irq = self.mmu.int_flag & (1 << i) > 0;
-                let ie = self.mmu.int_enable & (1 << i) > 0;
-    
-                // If interrupt is requested and enabled
-                if irq && ie {
-                    self.call_isr(i);
-                    break;
-                }
+    // Returns requested and enabled interrupts.
+    fn pending_irqs(&self) -> u8 {
+        self.mmu.int_flag & self.mmu.int_enable & 0x1f
+    }
+
+    // Checks IRQs and execute ISRs if requested.
+    fn check_irqs(&mut self) {
+        // Bit 0 has the highest priority
+        for i in 0..5 {
+            if self.pending_irqs() & (1 << i) > 0 {
+                self.call_isr(i);
+                break;
             }
         }
-    
-        // Calls requested interrupt service routine.
-        fn call_isr(&mut self, id: u8) {
-            // Reset corresponding bit in IF
-            self.mmu.int_flag &= !(1 << id);
-            // Clear IME (disable any further interrupts)
-            self.ime = false;
-            self.halted = false;
-    
-            let isr: u16 = match id {
-                0 => 0x40,
-                1 => 0x48,
-                2 => 0x50,
-                3 => 0x80,
-                4 => 0x70,
-                _ => panic!("Invalid IRQ id {}", id),
-            };
-    
-            self.cycle += 8;    
-            self._call(isr);
+    }
+
+    // Calls requested interrupt service routine.
+    fn call_isr(&mut self, id: u8) {
+        // Reset corresponding bit in IF
+        self.mmu.int_flag &= !(1 << id);
+        // Clear IME (disable any further interrupts)
+        self.ime = false;
+        self.ime_delay = 0;
+        // After EI; HALT with a pending interrupt, the return address is the
+        // HALT, whose PC increment was skipped by the HALT bug
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
         }
 
+        let isr: u16 = match id {
+            0 => 0x40,
+            1 => 0x48,
+            2 => 0x50,
+            3 => 0x58,
+            4 => 0x60,
+            _ => panic!("Invalid IRQ id {}", id),
+        };
+
+        // 2 wait cycles, PC push (12 cycles incl. SP decrement) and jump: 20 cycles
+        self.cycle += 8;
+        self._call(isr);
+    }
+
     fn fetch_and_exec(&mut self) {
         let opcode = self.read_d8();
+
+        // PC fails to increment after HALT bug
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
+
         let reg = opcode & 7;
         let reg2 = opcode >> 3 & 7;
 
@@ -1255,12 +1485,178 @@ impl CPU {
 
             // HALT
             0x76 => self.halt(),
-            _ => println!("Unimplemented opcode"),
-            // _ => panic!("Unimplemented opcode 0x{:x}", opcode),
+
+            // STOP
+            0x10 => self.stop(),
+
+            // Illegal opcodes
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                self.lock_up(opcode)
+            }
         }
     }
 
+    fn stop(&mut self) {
+        // STOP is followed by a padding byte
+        self.read_d8();
+
+        // DIV is reset on entering STOP
+        self.mmu.write(0xff04, 0);
+
+        // On CGB, STOP performs a prepared speed switch instead
+        if !self.mmu.switch_speed() {
+            self.stopped = true;
+        }
+    }
+
+    fn lock_up(&mut self, opcode: u8) {
+        self.lockup = Some(Lockup {
+            pc: self.pc.wrapping_sub(1),
+            opcode,
+        });
+    }
+
     fn halt(&mut self) {
-        self.halted = true;
+        if !self.ime && self.pending_irqs() != 0 {
+            // HALT is not entered and the next byte is read twice
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::tests::program_rom;
+    use crate::gameboy::{Config, GameBoy};
+
+    // Creates a CPU running `program`, with interrupt service routines at
+    // the given vectors.
+    fn cpu(program: &[u8], isrs: &[(usize, &[u8])]) -> CPU {
+        let mut rom = program_rom(program);
+        for &(vector, isr) in isrs {
+            rom[vector..vector + isr.len()].copy_from_slice(isr);
+        }
+
+        let mut cpu = GameBoy::from_rom(rom, Config::default()).unwrap().cpu;
+        // Jump from the entry point to the program
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0150);
+        cpu
+    }
+
+    // Returns the 16-bit value on top of the stack.
+    fn stack_top(cpu: &mut CPU) -> u16 {
+        let sp = cpu.sp;
+        u16::from_le_bytes([cpu.mmu.read(sp), cpu.mmu.read(sp.wrapping_add(1))])
+    }
+
+    #[test]
+    fn vector_priority() {
+        let cases = [(0x1f, 0x40), (0x1e, 0x48), (0x1c, 0x50), (0x18, 0x58), (0x10, 0x60)];
+        for (int_flag, vector) in cases {
+            let mut cpu = cpu(&[0x18, 0xfe], &[]);
+            cpu.mmu.int_enable = 0x1f;
+            cpu.mmu.int_flag = int_flag;
+            cpu.ime = true;
+
+            cpu.step();
+            assert_eq!(cpu.pc, vector);
+            // Only the serviced interrupt is acknowledged
+            assert_eq!(cpu.mmu.int_flag & 0x1f, int_flag & (int_flag - 1));
+            assert!(!cpu.ime);
+        }
+    }
+
+    #[test]
+    fn dispatch_takes_20_cycles() {
+        let mut cpu = cpu(&[0x18, 0xfe], &[]);
+        cpu.mmu.int_enable = 0x04;
+        cpu.mmu.int_flag = 0x04;
+        cpu.ime = true;
+
+        assert_eq!(cpu.step(), 20);
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(stack_top(&mut cpu), 0x0150);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_next_instruction() {
+        // ei; nop; nop
+        let mut cpu = cpu(&[0xfb, 0x00, 0x00], &[]);
+        cpu.mmu.int_enable = 0x01;
+        cpu.mmu.int_flag = 0x01;
+
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0151);
+        assert!(!cpu.ime);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0152);
+        assert!(cpu.ime);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(stack_top(&mut cpu), 0x0152);
+    }
+
+    #[test]
+    fn halt_wakes_without_ime() {
+        // halt; inc b; jr $
+        let mut cpu = cpu(&[0x76, 0x04, 0x18, 0xfe], &[]);
+        cpu.mmu.int_enable = 0x04;
+        cpu.mmu.int_flag = 0x00;
+
+        cpu.step();
+        assert!(cpu.halted);
+        for _ in 0..10 {
+            assert_eq!(cpu.step(), 4);
+        }
+        assert_eq!(cpu.pc, 0x0151);
+
+        // The pending interrupt resumes execution without being serviced
+        cpu.mmu.int_flag = 0x04;
+        cpu.step();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, 0x0152);
+        assert_eq!(cpu.b, 1);
+        assert_eq!(cpu.mmu.int_flag & 0x1f, 0x04);
+    }
+
+    #[test]
+    fn halt_bug_reads_next_byte_twice() {
+        // halt; inc b; jr $
+        let mut cpu = cpu(&[0x76, 0x04, 0x18, 0xfe], &[]);
+        cpu.mmu.int_enable = 0x04;
+        cpu.mmu.int_flag = 0x04;
+
+        cpu.step();
+        assert!(!cpu.halted);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0151);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0152);
+        assert_eq!(cpu.b, 2);
+    }
+
+    #[test]
+    fn ei_halt_returns_to_halt() {
+        let program = [
+            0x3e, 0x01, // ld a,$01
+            0xe0, 0xff, // ldh ($ff),a
+            0xe0, 0x0f, // ldh ($0f),a
+            0xfb, // ei
+            0x76, // halt
+            0x18, 0xfe, // jr $
+        ];
+        // inc b; di; jr $
+        let isr: &[u8] = &[0x04, 0xf3, 0x18, 0xfe];
+        let mut cpu = cpu(&program, &[(0x40, isr)]);
+
+        for _ in 0..20 {
+            cpu.step();
+        }
+        assert_eq!(cpu.b, 1);
+        assert_eq!(cpu.pc, 0x42);
+        assert_eq!(stack_top(&mut cpu), 0x0157);
+    }
+}
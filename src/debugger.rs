@@ -0,0 +1,577 @@
+mod disasm;
+
+use crate::gameboy::{GameBoy, CLOCKS_PER_FRAME};
+use crate::mmu::Watchpoint;
+
+pub use disasm::disassemble;
+
+const HELP: &str = "\
+Addresses and values are hex, counts are decimal. An empty line repeats the last command.
+  c, continue            run until a breakpoint or watchpoint
+  p, pause               stop running
+  s, step [N]            execute N instructions (step into)
+  n, next                step over CALL and RST
+  o, out                 run until the current function returns
+  u, until ADDR          run until PC reaches ADDR
+  b, break ADDR          set a breakpoint on PC
+  w, watch ADDR[-END] [r|w|rw]
+                         stop on reads, writes (default) or any access
+  d, delete [ADDR]       delete breakpoints and watchpoints at ADDR, or all
+  i, info                list breakpoints and watchpoints
+  r, regs                show registers
+  set REG VAL            set a, f, b, c, d, e, h, l, af, bc, de, hl, sp, pc or ime
+  flag z|n|h|c 0|1       set a flag
+  x ADDR [N]             dump N bytes of memory (default 64)
+  poke ADDR VAL          write a byte to memory
+  dis [ADDR] [N]         disassemble N instructions (default 10 at PC)";
+
+// What the debugger does between frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Paused,
+    Running,
+    // Run until the CALL or RST at a step over returns to the next instruction
+    StepOver { pc: u16, sp: u16 },
+    // Run until a return pops the stack above SP
+    StepOut { sp: u16 },
+    // Run until PC reaches an address
+    RunTo(u16),
+}
+
+// Interactive debugger driven by text commands.
+pub struct Debugger {
+    mode: Mode,
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<Watchpoint>,
+    // Execution resumed, so the breakpoint at the current PC is skipped once
+    resumed: bool,
+    // Command repeated on an empty line
+    last_command: String,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    // Creates a debugger with execution paused.
+    pub fn new() -> Self {
+        Debugger {
+            mode: Mode::Paused,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            resumed: false,
+            last_command: String::new(),
+        }
+    }
+
+    // Returns true while execution is paused.
+    pub fn paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    // Runs one frame unless paused. Returns a report if execution stops at a
+    // breakpoint, a watchpoint or the end of a step.
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy) -> Option<String> {
+        let mut elapsed_tick: u32 = 0;
+
+        while self.mode != Mode::Paused && elapsed_tick < CLOCKS_PER_FRAME {
+            let regs = gameboy.cpu.registers();
+
+            if let Some(reason) = self.check_break(regs.pc, regs.sp) {
+                return Some(self.stop(gameboy, reason));
+            }
+            self.resumed = false;
+
+            let opcode = gameboy.cpu.mmu.peek(regs.pc);
+            elapsed_tick += gameboy.step();
+
+            if let Some(reason) = self.check_step(gameboy, regs.pc) {
+                return Some(self.stop(gameboy, reason));
+            }
+            if let Mode::StepOut { sp } = self.mode {
+                if disasm::is_return(opcode) && gameboy.cpu.registers().sp > sp {
+                    return Some(self.stop(gameboy, String::new()));
+                }
+            }
+        }
+
+        None
+    }
+
+    // Executes a command line. Returns the output to show.
+    pub fn command(&mut self, gameboy: &mut GameBoy, line: &str) -> String {
+        let line = line.trim();
+        let line = if line.is_empty() {
+            self.last_command.clone()
+        } else {
+            self.last_command = line.to_string();
+            line.to_string()
+        };
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+        self.execute(gameboy, &args).unwrap_or_else(|e| format!("Error: {}", e))
+    }
+
+    fn execute(&mut self, gameboy: &mut GameBoy, args: &[&str]) -> Result<String, String> {
+        match args {
+            [] => Ok(String::new()),
+            ["c" | "continue"] => {
+                self.resume(Mode::Running);
+                Ok(String::new())
+            }
+            ["p" | "pause"] => {
+                self.mode = Mode::Paused;
+                Ok(self.status(gameboy))
+            }
+            ["s" | "step"] => Ok(self.step_into(gameboy, 1)),
+            ["s" | "step", count] => match count.parse() {
+                Ok(count) => Ok(self.step_into(gameboy, count)),
+                Err(_) => Err(format!("invalid count {}", count)),
+            },
+            ["n" | "next"] => Ok(self.step_over(gameboy)),
+            ["o" | "out"] => {
+                let sp = gameboy.cpu.registers().sp;
+                self.resume(Mode::StepOut { sp });
+                Ok(String::new())
+            }
+            ["u" | "until", addr] => {
+                self.resume(Mode::RunTo(parse_hex(addr)?));
+                Ok(String::new())
+            }
+            ["b" | "break", addr] => {
+                let addr = parse_hex(addr)?;
+                if !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                }
+                Ok(format!("Breakpoint at ${:04x}", addr))
+            }
+            ["w" | "watch", range] => self.watch(gameboy, range, "w"),
+            ["w" | "watch", range, kind] => self.watch(gameboy, range, kind),
+            ["d" | "delete"] => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                gameboy.cpu.mmu.watchpoints.clear();
+                Ok("Deleted all breakpoints and watchpoints".to_string())
+            }
+            ["d" | "delete", addr] => {
+                let addr = parse_hex(addr)?;
+                self.breakpoints.retain(|&bp| bp != addr);
+                self.watchpoints.retain(|wp| wp.start != addr);
+                gameboy.cpu.mmu.watchpoints = self.watchpoints.clone();
+                Ok(format!("Deleted breakpoints and watchpoints at ${:04x}", addr))
+            }
+            ["i" | "info"] => Ok(self.info()),
+            ["r" | "regs"] => Ok(self.status(gameboy)),
+            ["set", reg, val] => {
+                self.set_register(gameboy, reg, parse_hex(val)?)?;
+                Ok(self.status(gameboy))
+            }
+            ["flag", flag, val] => {
+                let bit = match *flag {
+                    "z" => 0x80,
+                    "n" => 0x40,
+                    "h" => 0x20,
+                    "c" => 0x10,
+                    _ => return Err(format!("unknown flag {}", flag)),
+                };
+                let mut regs = gameboy.cpu.registers();
+                match *val {
+                    "0" => regs.f &= !bit,
+                    "1" => regs.f |= bit,
+                    _ => return Err(format!("flag value must be 0 or 1, found {}", val)),
+                }
+                gameboy.cpu.set_registers(regs);
+                Ok(self.status(gameboy))
+            }
+            ["x", addr] => Ok(dump(gameboy, parse_hex(addr)?, 64)),
+            ["x", addr, len] => match len.parse() {
+                Ok(len) => Ok(dump(gameboy, parse_hex(addr)?, len)),
+                Err(_) => Err(format!("invalid count {}", len)),
+            },
+            ["poke", addr, val] => {
+                let addr = parse_hex(addr)?;
+                let val = parse_byte(val)?;
+                gameboy.cpu.mmu.poke(addr, val);
+                Ok(format!("${:04x} = ${:02x}", addr, gameboy.cpu.mmu.peek(addr)))
+            }
+            ["dis"] => Ok(listing(gameboy, gameboy.cpu.registers().pc, 10)),
+            ["dis", addr] => Ok(listing(gameboy, parse_hex(addr)?, 10)),
+            ["dis", addr, count] => match count.parse() {
+                Ok(count) => Ok(listing(gameboy, parse_hex(addr)?, count)),
+                Err(_) => Err(format!("invalid count {}", count)),
+            },
+            ["h" | "help"] => Ok(HELP.to_string()),
+            [cmd, ..] => Err(format!("unknown command or arguments: {} (try help)", cmd)),
+        }
+    }
+
+    // Shows the registers and the next instruction.
+    pub fn status(&self, gameboy: &GameBoy) -> String {
+        let regs = gameboy.cpu.registers();
+        let flag = |bit: u8, name: char| if regs.f & bit > 0 { name } else { '-' };
+
+        format!(
+            "PC={:04x} SP={:04x} AF={:02x}{:02x} BC={:02x}{:02x} DE={:02x}{:02x} HL={:02x}{:02x} \
+             {}{}{}{} IME={}\n{}",
+            regs.pc,
+            regs.sp,
+            regs.a,
+            regs.f,
+            regs.b,
+            regs.c,
+            regs.d,
+            regs.e,
+            regs.h,
+            regs.l,
+            flag(0x80, 'Z'),
+            flag(0x40, 'N'),
+            flag(0x20, 'H'),
+            flag(0x10, 'C'),
+            u8::from(regs.ime),
+            disassemble_line(gameboy, regs.pc).0
+        )
+    }
+
+    fn resume(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.resumed = true;
+    }
+
+    // Pauses execution and reports why.
+    fn stop(&mut self, gameboy: &GameBoy, reason: String) -> String {
+        self.mode = Mode::Paused;
+
+        if reason.is_empty() {
+            self.status(gameboy)
+        } else {
+            format!("{}\n{}", reason, self.status(gameboy))
+        }
+    }
+
+    // Returns the reason to stop before executing the instruction at PC.
+    fn check_break(&self, pc: u16, sp: u16) -> Option<String> {
+        if self.resumed {
+            return None;
+        }
+        if self.breakpoints.contains(&pc) {
+            return Some(format!("Breakpoint at ${:04x}", pc));
+        }
+
+        match self.mode {
+            Mode::StepOver { pc: target, sp: target_sp } if pc == target && sp >= target_sp => {
+                Some(String::new())
+            }
+            Mode::RunTo(target) if pc == target => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    // Returns the reason to stop after executing the instruction at `pc`.
+    fn check_step(&self, gameboy: &mut GameBoy, pc: u16) -> Option<String> {
+        if let Some(hit) = gameboy.cpu.mmu.take_watch_hit() {
+            let access = if hit.write {
+                format!("write ${:02x} to", hit.val)
+            } else {
+                format!("read ${:02x} from", hit.val)
+            };
+            return Some(format!("Watchpoint: {} ${:04x} at ${:04x}", access, hit.addr, pc));
+        }
+
+        gameboy.lockup().map(|lockup| {
+            format!("CPU locked up: illegal opcode 0x{:02x} at 0x{:04x}", lockup.opcode, lockup.pc)
+        })
+    }
+
+    // Executes instructions while paused. Stops early at a watchpoint.
+    fn step_into(&mut self, gameboy: &mut GameBoy, count: u32) -> String {
+        self.mode = Mode::Paused;
+
+        for _ in 0..count {
+            let pc = gameboy.cpu.registers().pc;
+            gameboy.step();
+
+            if let Some(reason) = self.check_step(gameboy, pc) {
+                return self.stop(gameboy, reason);
+            }
+        }
+
+        self.status(gameboy)
+    }
+
+    // Executes the next instruction, running a called subroutine to its end.
+    fn step_over(&mut self, gameboy: &mut GameBoy) -> String {
+        let regs = gameboy.cpu.registers();
+
+        if !disasm::is_call(gameboy.cpu.mmu.peek(regs.pc)) {
+            return self.step_into(gameboy, 1);
+        }
+
+        let (_, len) = disassemble_line(gameboy, regs.pc);
+        self.resume(Mode::StepOver {
+            pc: regs.pc.wrapping_add(len),
+            sp: regs.sp,
+        });
+        String::new()
+    }
+
+    // Adds a watchpoint on an address or range such as c000-c0ff.
+    fn watch(&mut self, gameboy: &mut GameBoy, range: &str, kind: &str) -> Result<String, String> {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_hex(start)?, parse_hex(end)?),
+            None => (parse_hex(range)?, parse_hex(range)?),
+        };
+        if end < start {
+            return Err(format!("invalid range {}", range));
+        }
+
+        let (read, write) = match kind {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" => (true, true),
+            _ => return Err(format!("invalid watchpoint kind {}", kind)),
+        };
+
+        let watchpoint = Watchpoint {
+            start,
+            end,
+            read,
+            write,
+        };
+        self.watchpoints.push(watchpoint);
+        gameboy.cpu.mmu.watchpoints = self.watchpoints.clone();
+
+        Ok(format!("Watchpoint on {}", describe_watchpoint(&watchpoint)))
+    }
+
+    // Lists breakpoints and watchpoints.
+    fn info(&self) -> String {
+        let mut lines: Vec<String> = self
+            .breakpoints
+            .iter()
+            .map(|addr| format!("Breakpoint at ${:04x}", addr))
+            .collect();
+        lines.extend(
+            self.watchpoints
+                .iter()
+                .map(|watchpoint| format!("Watchpoint on {}", describe_watchpoint(watchpoint))),
+        );
+
+        if lines.is_empty() {
+            return "No breakpoints or watchpoints".to_string();
+        }
+        lines.join("\n")
+    }
+
+    fn set_register(&self, gameboy: &mut GameBoy, reg: &str, val: u16) -> Result<(), String> {
+        let mut regs = gameboy.cpu.registers();
+        let byte = || u8::try_from(val).map_err(|_| format!("${:x} does not fit in {}", val, reg));
+        let [hi, lo] = val.to_be_bytes();
+
+        match reg {
+            "a" => regs.a = byte()?,
+            "f" => regs.f = byte()?,
+            "b" => regs.b = byte()?,
+            "c" => regs.c = byte()?,
+            "d" => regs.d = byte()?,
+            "e" => regs.e = byte()?,
+            "h" => regs.h = byte()?,
+            "l" => regs.l = byte()?,
+            "af" => (regs.a, regs.f) = (hi, lo),
+            "bc" => (regs.b, regs.c) = (hi, lo),
+            "de" => (regs.d, regs.e) = (hi, lo),
+            "hl" => (regs.h, regs.l) = (hi, lo),
+            "sp" => regs.sp = val,
+            "pc" => regs.pc = val,
+            "ime" => regs.ime = val != 0,
+            _ => return Err(format!("unknown register {}", reg)),
+        }
+
+        gameboy.cpu.set_registers(regs);
+        Ok(())
+    }
+}
+
+// Disassembles the instruction at an address with its bytes. Returns the
+// line and the instruction length.
+fn disassemble_line(gameboy: &GameBoy, addr: u16) -> (String, u16) {
+    let mmu = &gameboy.cpu.mmu;
+    let (text, len) = disassemble(|addr| mmu.peek(addr), addr);
+    let bytes: Vec<String> = (0..len)
+        .map(|i| format!("{:02x}", mmu.peek(addr.wrapping_add(i))))
+        .collect();
+
+    (format!("{:04x}: {:<9} {}", addr, bytes.join(" "), text), len)
+}
+
+// Disassembles a number of instructions.
+fn listing(gameboy: &GameBoy, addr: u16, count: usize) -> String {
+    let mut addr = addr;
+    let mut lines = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let (line, len) = disassemble_line(gameboy, addr);
+        lines.push(line);
+        addr = addr.wrapping_add(len);
+    }
+    lines.join("\n")
+}
+
+// Dumps memory as hex, 16 bytes per line.
+fn dump(gameboy: &GameBoy, addr: u16, len: usize) -> String {
+    let mut lines = Vec::new();
+
+    for row in (0..len).step_by(16) {
+        let row_addr = addr.wrapping_add(row as u16);
+        let bytes: Vec<String> = (0..(len - row).min(16) as u16)
+            .map(|i| format!("{:02x}", gameboy.cpu.mmu.peek(row_addr.wrapping_add(i))))
+            .collect();
+        lines.push(format!("{:04x}: {}", row_addr, bytes.join(" ")));
+    }
+    lines.join("\n")
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let kind = match (watchpoint.read, watchpoint.write) {
+        (true, true) => "access",
+        (true, false) => "read",
+        _ => "write",
+    };
+
+    if watchpoint.start == watchpoint.end {
+        format!("${:04x} ({})", watchpoint.start, kind)
+    } else {
+        format!("${:04x}-${:04x} ({})", watchpoint.start, watchpoint.end, kind)
+    }
+}
+
+// Parses a hex number, optionally prefixed with $ or 0x.
+fn parse_hex(s: &str) -> Result<u16, String> {
+    let digits = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")).unwrap_or(s);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid hex number {}", s))
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    u8::try_from(parse_hex(s)?).map_err(|_| format!("{} does not fit in a byte", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::tests::program_rom;
+    use crate::gameboy::Config;
+
+    // Returns a paused debugger and a Game Boy at the start of this program.
+    fn debugger() -> (Debugger, GameBoy) {
+        let mut program = vec![
+            0x21, 0x00, 0xc0, // $0150: ld hl,$c000
+            0xcd, 0x60, 0x01, // $0153: call $0160
+            0x7e, // $0156: ld a,(hl)
+            0x18, 0xfe, // $0157: jr $0157
+        ];
+        program.resize(0x10, 0x00);
+        program.extend_from_slice(&[
+            0x04, // $0160: inc b
+            0x34, // $0161: inc (hl)
+            0xc9, // $0162: ret
+        ]);
+
+        let mut gameboy = GameBoy::from_rom(program_rom(&program), Config::default()).unwrap();
+        let mut debugger = Debugger::new();
+        // Jump from the entry point to the program
+        debugger.command(&mut gameboy, "s");
+        assert_eq!(gameboy.cpu.registers().pc, 0x0150);
+        (debugger, gameboy)
+    }
+
+    #[test]
+    fn commands() {
+        let (mut debugger, mut gameboy) = debugger();
+        let mut command = |line: &str| debugger.command(&mut gameboy, line);
+
+        assert_eq!(command("b 157"), "Breakpoint at $0157");
+        assert_eq!(command("w $c000-c0ff rw"), "Watchpoint on $c000-$c0ff (access)");
+        assert_eq!(command("w 0xff80"), "Watchpoint on $ff80 (write)");
+        assert_eq!(
+            command("i"),
+            "Breakpoint at $0157\nWatchpoint on $c000-$c0ff (access)\nWatchpoint on $ff80 (write)"
+        );
+        assert_eq!(command("d 157"), "Deleted breakpoints and watchpoints at $0157");
+        assert_eq!(command("d"), "Deleted all breakpoints and watchpoints");
+        assert_eq!(command("i"), "No breakpoints or watchpoints");
+
+        assert_eq!(command("b xyz"), "Error: invalid hex number xyz");
+        assert_eq!(command("w c0ff-c000"), "Error: invalid range c0ff-c000");
+        assert_eq!(command("w c000 x"), "Error: invalid watchpoint kind x");
+        assert_eq!(command("s -1"), "Error: invalid count -1");
+        assert_eq!(command("set a 100"), "Error: $100 does not fit in a");
+        assert_eq!(command("poke c000 100"), "Error: 100 does not fit in a byte");
+        assert!(command("frobnicate").starts_with("Error: unknown command"));
+
+        assert!(command("poke c000 42").starts_with("$c000 = $42"));
+        assert_eq!(command("x c000 2"), "c000: 42 00");
+        assert_eq!(command("dis 150 2"), "0150: 21 00 c0  ld hl,$c000\n0153: cd 60 01  call $0160");
+    }
+
+    #[test]
+    fn empty_line_repeats_last_command() {
+        let (mut debugger, mut gameboy) = debugger();
+        debugger.command(&mut gameboy, "s");
+        assert_eq!(gameboy.cpu.registers().pc, 0x0153);
+        debugger.command(&mut gameboy, "");
+        assert_eq!(gameboy.cpu.registers().pc, 0x0160);
+    }
+
+    #[test]
+    fn step_over() {
+        let (mut debugger, mut gameboy) = debugger();
+        // Other instructions are stepped into
+        debugger.command(&mut gameboy, "n");
+        assert_eq!(gameboy.cpu.registers().pc, 0x0153);
+
+        assert_eq!(debugger.command(&mut gameboy, "n"), "");
+        let report = debugger.run_frame(&mut gameboy).unwrap();
+        assert!(report.starts_with("PC=0156"), "{}", report);
+        assert_eq!(gameboy.cpu.registers().b, 1);
+        assert!(debugger.paused());
+    }
+
+    #[test]
+    fn step_out() {
+        let (mut debugger, mut gameboy) = debugger();
+        debugger.command(&mut gameboy, "s 3");
+        assert_eq!(gameboy.cpu.registers().pc, 0x0161);
+
+        debugger.command(&mut gameboy, "o");
+        let report = debugger.run_frame(&mut gameboy).unwrap();
+        assert!(report.starts_with("PC=0156"), "{}", report);
+        assert_eq!(gameboy.cpu.mmu.peek(0xc000), 1);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (mut debugger, mut gameboy) = debugger();
+        debugger.command(&mut gameboy, "b 161");
+        debugger.command(&mut gameboy, "c");
+        let report = debugger.run_frame(&mut gameboy).unwrap();
+        assert!(report.starts_with("Breakpoint at $0161\nPC=0161"), "{}", report);
+
+        // Continuing leaves the breakpoint, which isn't reached again
+        debugger.command(&mut gameboy, "c");
+        assert_eq!(debugger.run_frame(&mut gameboy), None);
+        assert!(!debugger.paused());
+    }
+
+    #[test]
+    fn read_watchpoints_ignore_instruction_fetches() {
+        let (mut debugger, mut gameboy) = debugger();
+        debugger.command(&mut gameboy, "w 150-162 r");
+        debugger.command(&mut gameboy, "w c000 r");
+        debugger.command(&mut gameboy, "c");
+
+        let report = debugger.run_frame(&mut gameboy).unwrap();
+        assert!(report.starts_with("Watchpoint: read $00 from $c000 at $0161"), "{}", report);
+    }
+}
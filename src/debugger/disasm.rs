@@ -0,0 +1,158 @@
+// Operand names indexed by the register bits of an opcode.
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "(hl)", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add a,", "adc a,", "sub ", "sbc a,", "and ", "xor ", "or ", "cp "];
+const ROTATE: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const CB_SHIFT: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+// Opcodes which call a subroutine: CALL, CALL cc and RST.
+pub fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xcd | 0xc4 | 0xcc | 0xd4 | 0xdc) || opcode & 0xc7 == 0xc7
+}
+
+// Opcodes which return from a subroutine: RET, RET cc and RETI.
+pub fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xc9 | 0xd9 | 0xc0 | 0xc8 | 0xd0 | 0xd8)
+}
+
+// Disassembles the instruction at an address. Returns its text and length.
+pub fn disassemble(read: impl Fn(u16) -> u8, addr: u16) -> (String, u16) {
+    let opcode = read(addr);
+    let d8 = read(addr.wrapping_add(1));
+    let d16 = u16::from_le_bytes([d8, read(addr.wrapping_add(2))]);
+    // Target of a relative jump
+    let rel = addr.wrapping_add(2).wrapping_add(d8 as i8 as u16);
+
+    let y = ((opcode >> 3) & 0x7) as usize;
+    let z = (opcode & 0x7) as usize;
+    let p = y >> 1;
+
+    match opcode {
+        0x00 => ("nop".to_string(), 1),
+        0x08 => (format!("ld (${:04x}),sp", d16), 3),
+        0x10 => ("stop".to_string(), 2),
+        0x18 => (format!("jr ${:04x}", rel), 2),
+        0x20 | 0x28 | 0x30 | 0x38 => (format!("jr {},${:04x}", CONDITIONS[y - 4], rel), 2),
+        0x01 | 0x11 | 0x21 | 0x31 => (format!("ld {},${:04x}", R16[p], d16), 3),
+        0x09 | 0x19 | 0x29 | 0x39 => (format!("add hl,{}", R16[p]), 1),
+        0x02 => ("ld (bc),a".to_string(), 1),
+        0x12 => ("ld (de),a".to_string(), 1),
+        0x22 => ("ld (hl+),a".to_string(), 1),
+        0x32 => ("ld (hl-),a".to_string(), 1),
+        0x0a => ("ld a,(bc)".to_string(), 1),
+        0x1a => ("ld a,(de)".to_string(), 1),
+        0x2a => ("ld a,(hl+)".to_string(), 1),
+        0x3a => ("ld a,(hl-)".to_string(), 1),
+        0x03 | 0x13 | 0x23 | 0x33 => (format!("inc {}", R16[p]), 1),
+        0x0b | 0x1b | 0x2b | 0x3b => (format!("dec {}", R16[p]), 1),
+        0x00..=0x3f if z == 4 => (format!("inc {}", R8[y]), 1),
+        0x00..=0x3f if z == 5 => (format!("dec {}", R8[y]), 1),
+        0x00..=0x3f if z == 6 => (format!("ld {},${:02x}", R8[y], d8), 2),
+        0x07 | 0x0f | 0x17 | 0x1f | 0x27 | 0x2f | 0x37 | 0x3f => (ROTATE[y].to_string(), 1),
+        0x76 => ("halt".to_string(), 1),
+        0x40..=0x7f => (format!("ld {},{}", R8[y], R8[z]), 1),
+        0x80..=0xbf => (format!("{}{}", ALU[y], R8[z]), 1),
+        0xc0 | 0xc8 | 0xd0 | 0xd8 => (format!("ret {}", CONDITIONS[y]), 1),
+        0xe0 => (format!("ldh ($ff{:02x}),a", d8), 2),
+        0xe8 => (format!("add sp,{}", d8 as i8), 2),
+        0xf0 => (format!("ldh a,($ff{:02x})", d8), 2),
+        0xf8 => (format!("ld hl,sp{:+}", d8 as i8), 2),
+        0xc1 | 0xd1 | 0xe1 | 0xf1 => (format!("pop {}", R16_STACK[p]), 1),
+        0xc9 => ("ret".to_string(), 1),
+        0xd9 => ("reti".to_string(), 1),
+        0xe9 => ("jp hl".to_string(), 1),
+        0xf9 => ("ld sp,hl".to_string(), 1),
+        0xc2 | 0xca | 0xd2 | 0xda => (format!("jp {},${:04x}", CONDITIONS[y], d16), 3),
+        0xe2 => ("ld ($ff00+c),a".to_string(), 1),
+        0xea => (format!("ld (${:04x}),a", d16), 3),
+        0xf2 => ("ld a,($ff00+c)".to_string(), 1),
+        0xfa => (format!("ld a,(${:04x})", d16), 3),
+        0xc3 => (format!("jp ${:04x}", d16), 3),
+        0xcb => (disassemble_cb(d8), 2),
+        0xf3 => ("di".to_string(), 1),
+        0xfb => ("ei".to_string(), 1),
+        0xc4 | 0xcc | 0xd4 | 0xdc => (format!("call {},${:04x}", CONDITIONS[y], d16), 3),
+        0xc5 | 0xd5 | 0xe5 | 0xf5 => (format!("push {}", R16_STACK[p]), 1),
+        0xcd => (format!("call ${:04x}", d16), 3),
+        0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => {
+            (format!("{}${:02x}", ALU[y], d8), 2)
+        }
+        0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => (format!("rst ${:02x}", y * 8), 1),
+        // Illegal opcodes
+        _ => (format!("db ${:02x}", opcode), 1),
+    }
+}
+
+// Disassembles the second byte of a CB-prefixed instruction.
+fn disassemble_cb(opcode: u8) -> String {
+    let y = ((opcode >> 3) & 0x7) as usize;
+    let reg = R8[(opcode & 0x7) as usize];
+
+    match opcode >> 6 {
+        0 => format!("{} {}", CB_SHIFT[y], reg),
+        1 => format!("bit {},{}", y, reg),
+        2 => format!("res {},{}", y, reg),
+        _ => format!("set {},{}", y, reg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Disassembles bytes placed at an address.
+    fn disassemble_bytes(bytes: &[u8], addr: u16) -> (String, u16) {
+        disassemble(|a| bytes.get(a.wrapping_sub(addr) as usize).copied().unwrap_or(0), addr)
+    }
+
+    #[test]
+    fn instructions() {
+        let cases: [(&[u8], &str); 20] = [
+            (&[0x00], "nop"),
+            (&[0x21, 0x00, 0xc0], "ld hl,$c000"),
+            (&[0x08, 0x34, 0x12], "ld ($1234),sp"),
+            (&[0x36, 0x12], "ld (hl),$12"),
+            (&[0x2a], "ld a,(hl+)"),
+            (&[0x7e], "ld a,(hl)"),
+            (&[0x76], "halt"),
+            (&[0xa8], "xor b"),
+            (&[0xc6, 0x01], "add a,$01"),
+            (&[0xe0, 0x40], "ldh ($ff40),a"),
+            (&[0xe8, 0xfe], "add sp,-2"),
+            (&[0xf8, 0x02], "ld hl,sp+2"),
+            (&[0xcb, 0x7c], "bit 7,h"),
+            (&[0xcb, 0x37], "swap a"),
+            (&[0xcd, 0x60, 0x01], "call $0160"),
+            (&[0xdc, 0x60, 0x01], "call c,$0160"),
+            (&[0xc0], "ret nz"),
+            (&[0xf5], "push af"),
+            (&[0xff], "rst $38"),
+            (&[0xd3], "db $d3"),
+        ];
+
+        for (bytes, text) in cases {
+            assert_eq!(disassemble_bytes(bytes, 0x0150), (text.to_string(), bytes.len() as u16));
+        }
+    }
+
+    #[test]
+    fn relative_jumps() {
+        assert_eq!(disassemble_bytes(&[0x18, 0xfe], 0x0157), ("jr $0157".to_string(), 2));
+        assert_eq!(disassemble_bytes(&[0x20, 0x05], 0x0150), ("jr nz,$0157".to_string(), 2));
+        assert_eq!(disassemble_bytes(&[0x38, 0x80], 0x0000), ("jr c,$ff82".to_string(), 2));
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        for opcode in [0xcd, 0xc4, 0xcc, 0xd4, 0xdc, 0xc7, 0xef, 0xff] {
+            assert!(is_call(opcode), "{:02x}", opcode);
+        }
+        for opcode in [0xc9, 0xd9, 0xc0, 0xc8, 0xd0, 0xd8] {
+            assert!(is_return(opcode), "{:02x}", opcode);
+            assert!(!is_call(opcode), "{:02x}", opcode);
+        }
+        assert!(!is_call(0xc3) && !is_return(0xe9));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::cpu::{Lockup, CPU};
+use crate::joypad::Key;
+use crate::ppu::Renderer;
+use crate::state::{self, StateError};
+
+// Width of the screen in pixels.
+pub const SCREEN_WIDTH: usize = 160;
+// Height of the screen in pixels.
+pub const SCREEN_HEIGHT: usize = 144;
+// Clocks per frame (154 lines of 456 clocks).
+pub const CLOCKS_PER_FRAME: u32 = 456 * 154;
+
+// Settings used when loading a cartridge.
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Reject ROMs with a bad header checksum
+    pub strict: bool,
+    // DMG or CGB boot ROM image to run before the cartridge
+    pub boot_rom: Option<Vec<u8>>,
+    // CGB mode override, selected from the cartridge header if unset
+    pub cgb: Option<bool>,
+    pub renderer: Renderer,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            strict: false,
+            boot_rom: None,
+            cgb: None,
+            renderer: Renderer::Scanline,
+        }
+    }
+}
+
+// Game Boy emulator.
+pub struct GameBoy {
+    pub cpu: CPU,
+}
+
+impl GameBoy {
+    // Loads a ROM file, which may be in a zip or gzip archive.
+    pub fn load(rom_name: &str, config: Config) -> Result<Self, CartridgeError> {
+        let cpu = CPU::new(rom_name, config.strict, config.boot_rom, config.cgb, config.renderer)?;
+
+        Ok(GameBoy { cpu })
+    }
+
+    // Loads ROM data from memory. Battery-backed RAM is not saved.
+    pub fn from_rom(rom: impl Into<Vec<u8>>, config: Config) -> Result<Self, CartridgeError> {
+        let cartridge = Cartridge::from_rom(rom, config.strict)?;
+        let cpu = CPU::with_cartridge(cartridge, config.boot_rom, config.cgb, config.renderer);
+
+        Ok(GameBoy { cpu })
+    }
+
+    // Executes one instruction or interrupt dispatch. Returns the elapsed
+    // clocks at normal speed.
+    pub fn step(&mut self) -> u32 {
+        self.cpu.step() as u32
+    }
+
+    // Runs the emulation for one frame.
+    pub fn run_frame(&mut self) {
+        let mut elapsed_tick: u32 = 0;
+
+        while elapsed_tick < CLOCKS_PER_FRAME {
+            elapsed_tick += self.cpu.step() as u32;
+        }
+    }
+
+    // Returns the last frame as RGB pixels, 3 bytes per pixel.
+    pub fn frame_buffer(&self) -> &[u8] {
+        self.cpu.mmu.ppu.frame_buffer()
+    }
+
+    // Sets the audio output sample rate.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.mmu.apu.set_sample_rate(sample_rate);
+    }
+
+    // Returns the audio samples produced since the last call, interleaved
+    // stereo.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.cpu.mmu.apu.take_samples()
+    }
+
+    pub fn keydown(&mut self, key: Key) {
+        self.cpu.mmu.joypad.keydown(key);
+    }
+
+    pub fn keyup(&mut self, key: Key) {
+        self.cpu.mmu.joypad.keyup(key);
+    }
+
+    // Returns the lock-up state, if an illegal opcode has been executed.
+    pub fn lockup(&self) -> Option<Lockup> {
+        self.cpu.lockup()
+    }
+
+    // Serializes the state of the machine, with a thumbnail of the current
+    // frame in the header.
+    pub fn save_state(&self) -> Vec<u8> {
+        state::save(&self.cpu)
+    }
+
+    // Restores a state created by `save_state` with the same ROM. The
+    // machine is left unchanged if the state can't be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = state::save(&self.cpu);
+
+        if let Err(e) = state::load(&mut self.cpu, data) {
+            state::load(&mut self.cpu, &backup).expect("Failed to restore state");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Writes battery-backed RAM to the save file.
+    pub fn write_save_file(&mut self) {
+        self.cpu.mmu.cartridge.write_save_file();
+    }
+
+    // Writes the save file if battery-backed RAM has changed.
+    pub fn flush_save_file(&mut self) {
+        self.cpu.mmu.cartridge.flush_save_file();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cartridge::tests::build_rom;
+
+    // Builds an MBC1 ROM with 128KB ROM and 8KB RAM that jumps from the entry
+    // point to `program` at 0x0150.
+    pub(crate) fn program_rom(program: &[u8]) -> Vec<u8> {
+        let mut rom = build_rom(0x03, 0x02, 0x02);
+        rom[0x0100..0x0103].copy_from_slice(&[0xc3, 0x50, 0x01]);
+        rom[0x0150..0x0150 + program.len()].copy_from_slice(program);
+        rom
+    }
+
+    #[test]
+    fn runs_rom_from_memory() {
+        let program = [
+            0x3e, 0x42, // ld a,$42
+            0xea, 0x00, 0xc0, // ld ($c000),a
+            0x3e, 0x03, // ld a,$03
+            0xea, 0x00, 0x20, // ld ($2000),a
+            0xfa, 0x00, 0x40, // ld a,($4000)
+            0xea, 0x01, 0xc0, // ld ($c001),a
+            0x18, 0xfe, // jr $
+        ];
+
+        let mut gameboy = GameBoy::from_rom(program_rom(&program), Config::default()).unwrap();
+        gameboy.run_frame();
+
+        assert!(gameboy.lockup().is_none());
+        assert_eq!(gameboy.cpu.registers().pc, 0x0160);
+        assert_eq!(gameboy.cpu.mmu.read(0xc000), 0x42);
+        // Bank 3 was mapped by the program
+        assert_eq!(gameboy.cpu.mmu.read(0xc001), 0x03);
+    }
+
+    #[test]
+    fn post_boot_io_registers() {
+        let gameboy = GameBoy::from_rom(build_rom(0x00, 0x00, 0x00), Config::default()).unwrap();
+
+        // Channel 1 is on, the PPU is in V-Blank with LY = LYC = 0
+        assert_eq!(gameboy.cpu.mmu.read(0xff26), 0xf1);
+        assert_eq!(gameboy.cpu.mmu.read(0xff41), 0x85);
+        assert_eq!(gameboy.cpu.mmu.read(0xff44), 0x00);
+    }
+
+    #[test]
+    fn from_rom_reports_cartridge_errors() {
+        let result = GameBoy::from_rom(vec![0; 0x20], Config::default());
+        assert!(matches!(result, Err(CartridgeError::TooShort(0x20))));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::bus::Bus;
+use crate::state::{StateError, StateReader, StateWriter};
 
 pub struct Joypad {
     // Joypad
@@ -21,6 +22,12 @@ pub enum Key {
     A,
 }
 
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
 impl Joypad {
     pub fn new() -> Self {
         Joypad {
@@ -45,6 +52,11 @@ impl Joypad {
         self.irq = true;
     }
 
+    // Returns true if any key is held down.
+    pub fn any_pressed(&self) -> bool {
+        self.key_state != 0xff
+    }
+
     pub fn keyup(&mut self, key: Key) {
         match key {
             Key::Down => self.key_state |= 0x80,
@@ -57,6 +69,18 @@ impl Joypad {
             Key::A => self.key_state |= 0x01,
         }
     }
+
+    // Keys held down are not part of the state, they follow the keyboard.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.joyp);
+        w.bool(self.irq);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.joyp = r.u8()?;
+        self.irq = r.bool()?;
+        Ok(())
+    }
 }
 
 impl Bus for Joypad {
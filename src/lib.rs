@@ -0,0 +1,21 @@
+pub mod apu;
+mod archive;
+pub mod boot;
+pub mod bus;
+pub mod cartridge;
+pub mod cli;
+pub mod cpu;
+pub mod debugger;
+mod gameboy;
+pub mod joypad;
+pub mod link;
+pub mod mapper;
+pub mod mmu;
+pub mod palette;
+pub mod ppu;
+pub mod rewind;
+pub mod serial;
+pub mod state;
+mod timer;
+
+pub use gameboy::{Config, GameBoy, CLOCKS_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
@@ -0,0 +1,161 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+// Message exchanged between two linked Game Boys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkMessage {
+    // Byte sent by the clock master
+    Data(u8),
+    // Byte shifted back by the clock slave
+    Reply(u8),
+    // Sent by both sides once per frame to keep emulation in step
+    Sync,
+}
+
+impl LinkMessage {
+    fn encode(&self) -> [u8; 2] {
+        match *self {
+            LinkMessage::Data(val) => [0, val],
+            LinkMessage::Reply(val) => [1, val],
+            LinkMessage::Sync => [2, 0],
+        }
+    }
+
+    fn decode(buf: [u8; 2]) -> Option<Self> {
+        match buf[0] {
+            0 => Some(LinkMessage::Data(buf[1])),
+            1 => Some(LinkMessage::Reply(buf[1])),
+            2 => Some(LinkMessage::Sync),
+            _ => None,
+        }
+    }
+}
+
+// Connection to another Game Boy.
+pub trait Link {
+    // Sends a message to the partner.
+    fn send(&mut self, msg: LinkMessage);
+
+    // Returns a received message, if any, without blocking.
+    fn try_recv(&mut self) -> Option<LinkMessage>;
+
+    // Waits for a message. Returns None on timeout or disconnection.
+    fn recv_timeout(&mut self, timeout: Duration) -> Option<LinkMessage>;
+}
+
+// Link cable over a TCP socket.
+pub struct TcpLink {
+    stream: TcpStream,
+    // Messages received by the reader thread
+    rx: Receiver<LinkMessage>,
+}
+
+impl TcpLink {
+    // Waits for a partner to connect to `addr`.
+    pub fn listen(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        TcpLink::new(stream)
+    }
+
+    // Connects to a partner listening on `addr`.
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        TcpLink::new(TcpStream::connect(addr)?)
+    }
+
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        // Byte exchanges are latency bound
+        stream.set_nodelay(true)?;
+
+        let mut reader = stream.try_clone()?;
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut buf = [0; 2];
+            while reader.read_exact(&mut buf).is_ok() {
+                match LinkMessage::decode(buf) {
+                    Some(msg) if tx.send(msg).is_ok() => (),
+                    _ => break,
+                }
+            }
+        });
+
+        Ok(TcpLink { stream, rx })
+    }
+}
+
+impl Link for TcpLink {
+    fn send(&mut self, msg: LinkMessage) {
+        // A lost partner behaves like an unplugged cable
+        let _ = self.stream.write_all(&msg.encode());
+    }
+
+    fn try_recv(&mut self) -> Option<LinkMessage> {
+        self.rx.try_recv().ok()
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> Option<LinkMessage> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+}
+
+impl Drop for TcpLink {
+    fn drop(&mut self) {
+        // Also ends the reader thread, so the partner sees the disconnection
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::bus::Bus;
+    use crate::serial::Serial;
+
+    // Returns two linked ends of a loopback connection.
+    fn loopback() -> (TcpLink, TcpLink) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let client = TcpLink::connect(&addr).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (TcpLink::new(stream).unwrap(), client)
+    }
+
+    // Runs a serial port until its transfer ends, giving the reader thread
+    // time to pass on messages.
+    fn run_transfer(serial: &mut Serial) {
+        let start = Instant::now();
+        while serial.read(0xff02) & 0x80 > 0 {
+            assert!(start.elapsed() < Duration::from_secs(5), "transfer timed out");
+            for _ in 0..128 {
+                serial.update(4);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn tcp_byte_exchange() {
+        let (master_link, slave_link) = loopback();
+        let mut master = Serial::new();
+        master.connect(Box::new(master_link));
+        let mut slave = Serial::new();
+        slave.connect(Box::new(slave_link));
+
+        slave.write(0xff01, 0x56);
+        slave.write(0xff02, 0x80);
+        master.write(0xff01, 0x12);
+        master.write(0xff02, 0x81);
+
+        run_transfer(&mut slave);
+        run_transfer(&mut master);
+        assert_eq!(slave.read(0xff01), 0x12);
+        assert_eq!(master.read(0xff01), 0x56);
+        assert!(master.irq && slave.irq);
+    }
+}
@@ -1,4 +1,9 @@
 use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc;
 
 extern crate sdl2;
 
@@ -6,20 +11,278 @@ use std::thread;
 use std::time;
 
 use sdl2::Sdl;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::render::Canvas;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 
-mod cartridge;
-mod bus;
-mod joypad;
-mod mmu;
-mod ppu;
-mod cpu;
-mod timer;
+use gbder::apu::APU;
+use gbder::cli;
+use gbder::debugger::Debugger;
+use gbder::joypad;
+use gbder::link::TcpLink;
+use gbder::palette::{self, Palette};
+use gbder::ppu::Renderer;
+use gbder::rewind::Rewind;
+use gbder::{Config, GameBoy, SCREEN_HEIGHT, SCREEN_WIDTH};
 
-use cpu::CPU;
+// Audio output sample rate in Hz.
+const SAMPLE_RATE: i32 = 48000;
+// Amount of queued audio to keep, in samples per channel (50 ms).
+const AUDIO_QUEUE_TARGET: u32 = SAMPLE_RATE as u32 / 20;
+// Maximum deviation of the APU sample rate for dynamic rate control.
+const MAX_RATE_DELTA: f64 = 0.005;
+// Frames between rewind snapshots.
+const REWIND_INTERVAL: u32 = 4;
+// Default rewind memory budget in MiB.
+const DEFAULT_REWIND_BUDGET: usize = 32;
+
+// Audio output fed by the APU.
+struct Audio {
+    queue: AudioQueue<f32>,
+    // Output volume (0.0-1.0)
+    volume: f32,
+    muted: bool,
+}
+
+impl Audio {
+    fn new(sdl_context: &Sdl, apu: &mut APU) -> Option<Self> {
+        let desired = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+
+        let queue = sdl_context
+            .audio()
+            .and_then(|audio| audio.open_queue::<f32, _>(None, &desired));
+        let queue = match queue {
+            Ok(queue) => queue,
+            Err(e) => {
+                eprintln!("Failed to open audio device, sound is disabled: {}", e);
+                return None;
+            }
+        };
+
+        apu.set_sample_rate(queue.spec().freq as u32);
+        queue.resume();
+
+        Some(Audio {
+            queue,
+            volume: 1.0,
+            muted: false,
+        })
+    }
+
+    // Returns the number of queued samples per channel.
+    fn queued(&self) -> u32 {
+        self.queue.size() / (std::mem::size_of::<f32>() as u32 * 2)
+    }
+
+    // Queues the samples produced by the APU.
+    fn push(&mut self, apu: &mut APU) {
+        // Dynamic rate control: produce slightly more samples when the queue
+        // runs low and slightly fewer when it fills up
+        let fill = self.queued() as f64 / AUDIO_QUEUE_TARGET as f64;
+        let ratio = 1.0 + MAX_RATE_DELTA * (1.0 - fill).clamp(-1.0, 1.0);
+        apu.set_sample_rate((self.queue.spec().freq as f64 * ratio) as u32);
+
+        let gain = if self.muted { 0.0 } else { self.volume };
+        let samples: Vec<f32> = apu.take_samples().iter().map(|s| s * gain).collect();
+
+        if let Err(e) = self.queue.queue_audio(&samples) {
+            eprintln!("Failed to queue audio: {}", e);
+        }
+    }
+
+    // Waits until the queued audio drains to the target amount.
+    fn wait(&self) {
+        while self.queued() > AUDIO_QUEUE_TARGET {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+    }
+
+    // Handles mute (M) and volume (-, =) hotkeys.
+    fn handle_keydown(&mut self, key: Keycode) {
+        match key {
+            Keycode::M => self.muted = !self.muted,
+            Keycode::Minus => self.volume = (self.volume - 0.1).max(0.0),
+            Keycode::Equals => self.volume = (self.volume + 0.1).min(1.0),
+            _ => (),
+        }
+    }
+}
+
+// DMG palettes selectable at runtime.
+struct Palettes {
+    palettes: Vec<Palette>,
+    current: usize,
+}
+
+impl Palettes {
+    // Creates the palette list from the presets and the user config file.
+    fn new(options: &Options) -> Self {
+        let mut palettes = palette::presets();
+
+        if let Some(fname) = &options.palette_fname {
+            match palette::load(fname) {
+                Ok(user_palettes) => palettes.extend(user_palettes),
+                Err(e) => {
+                    eprintln!("Failed to load palettes from {}: {}", fname, e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        Palettes {
+            palettes,
+            current: 0,
+        }
+    }
+
+    fn current(&self) -> &Palette {
+        &self.palettes[self.current]
+    }
+
+    // Switches to the next palette on the P hotkey. Returns true if switched.
+    fn handle_keydown(&mut self, key: Keycode) -> bool {
+        if key != Keycode::P {
+            return false;
+        }
+
+        self.current = (self.current + 1) % self.palettes.len();
+        println!("Palette: {}", self.current().name);
+        true
+    }
+}
+
+// Numbered save state slots, stored next to the ROM as game.ss1-game.ss9.
+struct SaveStates {
+    // ROM path without archive extension
+    path: PathBuf,
+}
+
+impl SaveStates {
+    fn new(rom_fname: &str) -> Self {
+        // game.gb.gz is saved to game.ss1 like game.gb
+        let mut path = PathBuf::from(rom_fname);
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz")) {
+            path.set_extension("");
+        }
+
+        SaveStates { path }
+    }
+
+    // Saves on Ctrl+F1-F9 and loads on F1-F9. The rewind history is
+    // discarded when a state is loaded. Returns true if handled.
+    fn handle_keydown(
+        &self,
+        gameboy: &mut GameBoy,
+        rewind: Option<&mut Rewind>,
+        key: Keycode,
+        keymod: Mod,
+    ) -> bool {
+        let slot = match key {
+            Keycode::F1 => 1,
+            Keycode::F2 => 2,
+            Keycode::F3 => 3,
+            Keycode::F4 => 4,
+            Keycode::F5 => 5,
+            Keycode::F6 => 6,
+            Keycode::F7 => 7,
+            Keycode::F8 => 8,
+            Keycode::F9 => 9,
+            _ => return false,
+        };
+        let path = self.path.with_extension(format!("ss{}", slot));
+
+        if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+            match fs::write(&path, gameboy.save_state()) {
+                Ok(_) => println!("Saved state {}", slot),
+                Err(e) => eprintln!("Failed to write {}: {}", path.display(), e),
+            }
+            return true;
+        }
+
+        let result = fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| gameboy.load_state(&data).map_err(|e| e.to_string()));
+        match result {
+            Ok(_) => {
+                if let Some(rewind) = rewind {
+                    rewind.clear();
+                }
+                println!("Loaded state {}", slot)
+            }
+            Err(e) => eprintln!("Failed to load {}: {}", path.display(), e),
+        }
+        true
+    }
+}
+
+// Debugger controlled by commands typed on stdin.
+struct Console {
+    debugger: Debugger,
+    // Lines read from stdin by a background thread
+    lines: mpsc::Receiver<String>,
+}
+
+impl Console {
+    // Starts reading commands from stdin. Emulation starts paused.
+    fn new(gameboy: &GameBoy) -> Self {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let debugger = Debugger::new();
+        println!("Debugger paused, type help for commands");
+        println!("{}", debugger.status(gameboy));
+
+        Console { debugger, lines }
+    }
+
+    // Executes the commands typed since the last frame.
+    fn handle_commands(&mut self, gameboy: &mut GameBoy) {
+        for line in self.lines.try_iter() {
+            let output = self.debugger.command(gameboy, &line);
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+        }
+    }
+
+    // Runs one frame unless paused. Returns true if emulation ran.
+    fn run_frame(&mut self, gameboy: &mut GameBoy) -> bool {
+        if self.debugger.paused() {
+            return false;
+        }
+
+        if let Some(report) = self.debugger.run_frame(gameboy) {
+            println!("{}", report);
+        }
+        true
+    }
+}
+
+// Runs one frame, under the debugger if enabled, then waits for the link
+// cable partner if it lags behind. Returns true if emulation ran.
+fn run_frame(gameboy: &mut GameBoy, console: Option<&mut Console>) -> bool {
+    let ran = match console {
+        Some(console) => console.run_frame(gameboy),
+        None => {
+            gameboy.run_frame();
+            true
+        }
+    };
+    gameboy.cpu.mmu.serial.wait_for_partner();
+    ran
+}
 
 fn translate_keycode(key: Keycode) -> Option<joypad::Key> {
     match key {
@@ -36,17 +299,116 @@ fn translate_keycode(key: Keycode) -> Option<joypad::Key> {
 }
 
 // Handles key down event.
-fn handle_keydown(cpu: &mut cpu::CPU, key: Keycode) {
-    translate_keycode(key).map(|k| cpu.mmu.joypad.keydown(k));
+fn handle_keydown(gameboy: &mut GameBoy, key: Keycode) {
+    translate_keycode(key).map(|k| gameboy.keydown(k));
 }
 
 // Handles key up event.
-fn handle_keyup(cpu: &mut cpu::CPU, key: Keycode) {
-    translate_keycode(key).map(|k| cpu.mmu.joypad.keyup(k));
+fn handle_keyup(gameboy: &mut GameBoy, key: Keycode) {
+    translate_keycode(key).map(|k| gameboy.keyup(k));
+}
+
+// Command line options.
+struct Options {
+    rom_fname: String,
+    // Address to wait for a link cable partner on
+    link_listen: Option<String>,
+    // Address of a link cable partner to connect to
+    link_connect: Option<String>,
+    // DMG or CGB boot ROM image
+    boot_rom_fname: Option<String>,
+    // CGB mode override, selected from the cartridge header if unset
+    cgb: Option<bool>,
+    // User palette config file
+    palette_fname: Option<String>,
+    renderer: Renderer,
+    // Reject ROMs with a bad header checksum
+    strict: bool,
+    // Rewind memory budget in MiB, 0 disables rewinding
+    rewind_budget: usize,
+    // Start paused with the debugger console on stdin
+    debug: bool,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: gbder [--strict] [--mode dmg|cgb] [--renderer scanline|fifo] [--boot-rom FILE] \
+         [--palettes FILE] [--rewind-budget MIB] [--debug] \
+         [--link-listen ADDR | --link-connect ADDR] ROM"
+    );
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut rom_fname = None;
+    let mut link_listen = None;
+    let mut link_connect = None;
+    let mut boot_rom_fname = None;
+    let mut cgb = None;
+    let mut palette_fname = None;
+    let mut renderer = Renderer::Scanline;
+    let mut strict = false;
+    let mut rewind_budget = DEFAULT_REWIND_BUDGET;
+    let mut debug = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--link-listen" => link_listen = Some(args.next().unwrap_or_else(|| usage())),
+            "--link-connect" => link_connect = Some(args.next().unwrap_or_else(|| usage())),
+            "--boot-rom" => boot_rom_fname = Some(args.next().unwrap_or_else(|| usage())),
+            "--palettes" => palette_fname = Some(args.next().unwrap_or_else(|| usage())),
+            "--strict" => strict = true,
+            "--debug" => debug = true,
+            "--rewind-budget" => {
+                let mib = args.next().and_then(|mib| mib.parse().ok());
+                rewind_budget = mib.unwrap_or_else(|| usage());
+            }
+            "--renderer" => {
+                let value = args.next().and_then(|value| cli::parse_renderer(&value));
+                renderer = value.unwrap_or_else(|| usage());
+            }
+            "--mode" => {
+                let value = args.next().and_then(|value| cli::parse_mode(&value));
+                cgb = Some(value.unwrap_or_else(|| usage()));
+            }
+            _ if arg.starts_with("--") => usage(),
+            _ => rom_fname = Some(arg),
+        }
+    }
+
+    Options {
+        rom_fname: rom_fname.unwrap_or_else(|| usage()),
+        link_listen,
+        link_connect,
+        boot_rom_fname,
+        cgb,
+        palette_fname,
+        renderer,
+        strict,
+        rewind_budget,
+        debug,
+    }
 }
 
-fn rom_fname() -> String {
-    env::args().nth(1).unwrap()
+// Opens the link cable connection requested on the command line.
+fn link_init(options: &Options) -> Option<TcpLink> {
+    let link = if let Some(addr) = &options.link_listen {
+        println!("Waiting for link cable partner on {}", addr);
+        TcpLink::listen(addr)
+    } else if let Some(addr) = &options.link_connect {
+        TcpLink::connect(addr)
+    } else {
+        return None;
+    };
+
+    match link {
+        Ok(link) => Some(link),
+        Err(e) => {
+            eprintln!("Failed to connect link cable: {}", e);
+            process::exit(1);
+        }
+    }
 }
 
 fn sdl_init(sdl_context: &Sdl) -> Canvas<sdl2::video::Window>  {
@@ -60,11 +422,30 @@ fn sdl_init(sdl_context: &Sdl) -> Canvas<sdl2::video::Window>  {
 }
 
 fn main() {
+    let options = parse_args();
+    let config = Config {
+        strict: options.strict,
+        boot_rom: cli::load_boot_rom(options.boot_rom_fname.as_deref()).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        }),
+        cgb: options.cgb,
+        renderer: options.renderer,
+    };
+    let mut gameboy = match GameBoy::load(&options.rom_fname, config) {
+        Ok(gameboy) => gameboy,
+        Err(e) => {
+            eprintln!("Failed to load ROM {}: {}", options.rom_fname, e);
+            process::exit(1);
+        }
+    };
+    let link = link_init(&options);
+
     let sdl_context = sdl2::init().unwrap();    
     let mut canvas = sdl_init(&sdl_context);
     let texture_creator = canvas.texture_creator();
     let mut texture = texture_creator
-        .create_texture_streaming(PixelFormatEnum::RGB24, 160, 144)
+        .create_texture_streaming(PixelFormatEnum::RGB24, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
         .unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
 
@@ -72,30 +453,60 @@ fn main() {
     canvas.copy(&texture, None, None).unwrap();
     canvas.present(); 
    
-    let mut cpu: CPU = CPU::new(&rom_fname());
+    if let Some(link) = link {
+        gameboy.cpu.mmu.serial.connect(Box::new(link));
+    }
+    let mut palettes = Palettes::new(&options);
+    gameboy.cpu.mmu.ppu.set_palette(palettes.current().clone());
+    let mut audio = Audio::new(&sdl_context, &mut gameboy.cpu.mmu.apu);
+    let save_states = SaveStates::new(&options.rom_fname);
+    let mut rewind = match options.rewind_budget {
+        0 => None,
+        budget => Some(Rewind::new(budget << 20, REWIND_INTERVAL)),
+    };
+    let mut console = options.debug.then(|| Console::new(&gameboy));
+    // Rewind hotkey is held
+    let mut rewinding = false;
+    let mut frames: u32 = 0;
 
     'running: loop {
         let now = time::Instant::now();
-        let mut elapsed_tick: u32 = 0;
 
-        // Emulate one frame
-        while elapsed_tick < 456 * (144 + 10) {
-            elapsed_tick += cpu.step() as u32;
-        }        
+        if let Some(console) = &mut console {
+            console.handle_commands(&mut gameboy);
+        }
+
+        // Emulate one frame, or play backwards while rewinding
+        let ran = match &mut rewind {
+            Some(rewind) if rewinding => rewind.rewind_frame(&mut gameboy),
+            Some(rewind) => {
+                let ran = run_frame(&mut gameboy, console.as_mut());
+                if ran {
+                    rewind.record(&gameboy);
+                }
+                ran
+            }
+            None => run_frame(&mut gameboy, console.as_mut()),
+        };
+
+        // The debugger reports lock-ups and stays open to inspect them
+        if let Some(lockup) = gameboy.lockup().filter(|_| console.is_none()) {
+            eprintln!(
+                "CPU locked up: illegal opcode 0x{:02x} at 0x{:04x}",
+                lockup.opcode, lockup.pc
+            );
+            gameboy.write_save_file();
+            break 'running;
+        }
 
         texture
             .with_lock(None, |buf: &mut [u8], pitch: usize| {
-                let fb = cpu.mmu.ppu.frame_buffer();
+                let fb = gameboy.frame_buffer();
+                let line = SCREEN_WIDTH * 3;
 
-                for y in 0..144 {
-                    for x in 0..160 {
-                        let offset = y * pitch + x * 3;
-                        let color = fb[y * 160 + x];
-
-                        buf[offset] = color;
-                        buf[offset + 1] = color;
-                        buf[offset + 2] = color;
-                    }
+                for y in 0..SCREEN_HEIGHT {
+                    let offset = y * pitch;
+                    buf[offset..offset + line].copy_from_slice(&fb[y * line..(y + 1) * line]);
                 }
             })
             .unwrap();
@@ -108,20 +519,50 @@ fn main() {
             match event {
                 Event::Quit {..} |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    gameboy.write_save_file();
                     break 'running
                 },
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => rewinding = true,
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => rewinding = false,
                 Event::KeyDown {
                     keycode: Some(keycode),
+                    keymod,
                     ..
-                } => handle_keydown(&mut cpu, keycode),
+                } => {
+                    if save_states.handle_keydown(&mut gameboy, rewind.as_mut(), keycode, keymod) {
+                        continue;
+                    }
+                    if let Some(audio) = &mut audio {
+                        audio.handle_keydown(keycode);
+                    }
+                    if palettes.handle_keydown(keycode) {
+                        gameboy.cpu.mmu.ppu.set_palette(palettes.current().clone());
+                    }
+                    handle_keydown(&mut gameboy, keycode)
+                }
                 Event::KeyUp {
                     keycode: Some(keycode),
                     ..
-                } => handle_keyup(&mut cpu, keycode),
+                } => handle_keyup(&mut gameboy, keycode),
                 _ => {}
             }
         }
 
+        // Flush battery-backed RAM about once a second
+        frames += 1;
+        if frames >= 60 {
+            frames = 0;
+            gameboy.flush_save_file();
+        }
+
+        // Pace emulation by the audio clock when sound is enabled. No sound
+        // is produced while rewinding or paused in the debugger.
+        if let Some(audio) = audio.as_mut().filter(|_| ran && !rewinding) {
+            audio.push(&mut gameboy.cpu.mmu.apu);
+            audio.wait();
+            continue;
+        }
+
         let wait = time::Duration::from_micros(1000000 / 60);
         let elapsed = now.elapsed();
         if wait > elapsed {
@@ -0,0 +1,103 @@
+use crate::cartridge::CartridgeType;
+
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod rom_only;
+
+use mbc1::Mbc1;
+use mbc2::Mbc2;
+use mbc3::Mbc3;
+use mbc5::Mbc5;
+use rom_only::RomOnly;
+
+pub use mbc2::MBC2_RAM_SIZE;
+pub use mbc3::{RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_32};
+
+// Size of a ROM bank in bytes.
+const ROM_BANK_SIZE: usize = 16 * 1024;
+// Size of a RAM bank in bytes.
+const RAM_BANK_SIZE: usize = 8 * 1024;
+
+// Memory bank controller of a cartridge.
+//
+// The cartridge owns ROM and RAM, a mapper only keeps its banking registers
+// and translates CPU addresses into offsets of them.
+pub trait Mapper {
+    // Reads ROM (0x0000-0x7fff) or external RAM (0xa000-0xbfff).
+    fn read(&self, rom: &[u8], ram: &[u8], addr: u16) -> u8;
+
+    // Writes a control register (0x0000-0x7fff) or external RAM (0xa000-0xbfff).
+    // Returns true if a byte of RAM was written.
+    fn write(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool;
+
+    // Advances internal clocks.
+    fn update(&mut self, _tick: u8) {}
+
+    // Serializes banking registers.
+    fn save_state(&self) -> Vec<u8>;
+
+    // Restores banking registers serialized by `save_state`.
+    fn load_state(&mut self, state: &[u8]);
+
+    // Serializes clock data appended to the battery save file.
+    fn save_rtc(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // Restores clock data read from the battery save file.
+    fn load_rtc(&mut self, _data: &[u8]) {}
+}
+
+// Creates the mapper for a cartridge type, or `None` if it is not supported.
+pub fn new_mapper(cartridge_type: &CartridgeType, rom_banks: u16) -> Option<Box<dyn Mapper>> {
+    let mapper: Box<dyn Mapper> = match cartridge_type {
+        CartridgeType::RomOnly | CartridgeType::RomRam | CartridgeType::RomRamBattery => {
+            Box::new(RomOnly::new())
+        }
+        CartridgeType::Mbc1 | CartridgeType::Mbc1Ram | CartridgeType::Mbc1RamBattery => {
+            Box::new(Mbc1::new(rom_banks))
+        }
+        CartridgeType::Mbc2 | CartridgeType::Mbc2Battery => Box::new(Mbc2::new(rom_banks)),
+        CartridgeType::Mbc3 | CartridgeType::Mbc3Ram | CartridgeType::Mbc3RamBattery => {
+            Box::new(Mbc3::new(rom_banks, false))
+        }
+        CartridgeType::Mbc3TimerBattery | CartridgeType::Mbc3TimerRamBattery => {
+            Box::new(Mbc3::new(rom_banks, true))
+        }
+        CartridgeType::Mbc5 | CartridgeType::Mbc5Ram | CartridgeType::Mbc5RamBattery => {
+            Box::new(Mbc5::new(rom_banks, false))
+        }
+        CartridgeType::Mbc5Rumble
+        | CartridgeType::Mbc5RumbleRam
+        | CartridgeType::Mbc5RumbleRamBattery => Box::new(Mbc5::new(rom_banks, true)),
+        _ => return None,
+    };
+
+    Some(mapper)
+}
+
+// Reads a byte from ROM bank `bank_no`.
+fn read_rom(rom: &[u8], bank_no: usize, addr: u16) -> u8 {
+    *rom.get(ROM_BANK_SIZE * bank_no + (addr & 0x3fff) as usize)
+        .unwrap_or(&0xff)
+}
+
+// Reads a byte from RAM bank `bank_no`.
+fn read_ram(ram: &[u8], bank_no: usize, addr: u16) -> u8 {
+    *ram.get(RAM_BANK_SIZE * bank_no + (addr & 0x1fff) as usize)
+        .unwrap_or(&0xff)
+}
+
+// Writes a byte to RAM bank `bank_no`. Returns false if the cartridge has no
+// RAM there.
+fn write_ram(ram: &mut [u8], bank_no: usize, addr: u16, val: u8) -> bool {
+    match ram.get_mut(RAM_BANK_SIZE * bank_no + (addr & 0x1fff) as usize) {
+        Some(byte) => {
+            *byte = val;
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,106 @@
+use super::{read_ram, read_rom, write_ram, Mapper};
+
+// MBC1 (max 2MB ROM and/or 32KB RAM).
+pub struct Mbc1 {
+    // Number of ROM banks
+    rom_banks: u16,
+    ram_enable: bool,
+    // ROM bank number (lower 5 bits)
+    bank_no_lower: u8,
+    // RAM bank number or ROM bank number (upper 2 bits)
+    bank_no_upper: u8,
+    // ROM/RAM mode select
+    mode: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom_banks: u16) -> Self {
+        Mbc1 {
+            rom_banks,
+            ram_enable: false,
+            bank_no_lower: 0,
+            bank_no_upper: 0,
+            mode: false,
+        }
+    }
+
+    fn rom_bank_no(&self) -> u8 {
+        let bank_no = if self.mode {
+            self.bank_no_lower
+        } else {
+            self.bank_no_upper << 5 | self.bank_no_lower
+        };
+
+        let bank_no = match bank_no {
+            0 | 0x20 | 0x40 | 0x60 => bank_no + 1,
+            _ => bank_no,
+        };
+
+        bank_no & (self.rom_banks - 1) as u8
+    }
+
+    fn ram_bank_no(&self) -> u8 {
+        if self.mode {
+            self.bank_no_upper
+        } else {
+            0
+        }
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn read(&self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            // ROM bank 00
+            0x0000..=0x3fff => read_rom(rom, 0, addr),
+            // ROM bank 01-7f
+            0x4000..=0x7fff => read_rom(rom, self.rom_bank_no() as usize, addr),
+            // RAM bank 00-03
+            0xa000..=0xbfff => {
+                if !self.ram_enable {
+                    return 0xff;
+                }
+                read_ram(ram, self.ram_bank_no() as usize, addr)
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn write(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        match addr {
+            // RAM enable
+            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
+            // ROM bank number (lower 5 bits)
+            0x2000..=0x3fff => self.bank_no_lower = val & 0x1f,
+            // RAM bank number or ROM bank number (upper 2 bits)
+            0x4000..=0x5fff => self.bank_no_upper = val & 0x03,
+            // ROM/RAM mode select
+            0x6000..=0x7fff => self.mode = val & 0x01 > 0,
+            // RAM bank 00-03
+            0xa000..=0xbfff => {
+                if !self.ram_enable {
+                    return false;
+                }
+                return write_ram(ram, self.ram_bank_no() as usize, addr, val);
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+        false
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![
+            u8::from(self.ram_enable),
+            self.bank_no_lower,
+            self.bank_no_upper,
+            u8::from(self.mode),
+        ]
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        self.ram_enable = state[0] > 0;
+        self.bank_no_lower = state[1];
+        self.bank_no_upper = state[2];
+        self.mode = state[3] > 0;
+    }
+}
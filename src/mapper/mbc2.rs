@@ -0,0 +1,85 @@
+use super::{read_rom, Mapper};
+
+// Size of built-in RAM (512x4 bits).
+pub const MBC2_RAM_SIZE: usize = 512;
+
+// MBC2 (max 256KB ROM and 512x4 bits RAM).
+pub struct Mbc2 {
+    // Number of ROM banks
+    rom_banks: u16,
+    ram_enable: bool,
+    // ROM bank number (4 bits)
+    rom_bank_no: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom_banks: u16) -> Self {
+        Mbc2 {
+            rom_banks,
+            ram_enable: false,
+            rom_bank_no: 1,
+        }
+    }
+
+    fn rom_bank_no(&self) -> u8 {
+        let bank_no = match self.rom_bank_no {
+            0 => 1,
+            n => n,
+        };
+
+        bank_no & (self.rom_banks - 1) as u8
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn read(&self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            // ROM bank 00
+            0x0000..=0x3fff => read_rom(rom, 0, addr),
+            // ROM bank 01-0f
+            0x4000..=0x7fff => read_rom(rom, self.rom_bank_no() as usize, addr),
+            // Built-in RAM, echoed every 512 bytes
+            0xa000..=0xbfff => {
+                if !self.ram_enable {
+                    return 0xff;
+                }
+                // Only the lower 4 bits are stored
+                0xf0 | ram[(addr & 0x01ff) as usize]
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn write(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        match addr {
+            // RAM enable (address bit 8 clear) or ROM bank number (address bit 8 set)
+            0x0000..=0x3fff => {
+                if addr & 0x0100 == 0 {
+                    self.ram_enable = val & 0x0f == 0x0a;
+                } else {
+                    self.rom_bank_no = val & 0x0f;
+                }
+            }
+            0x4000..=0x7fff => (),
+            // Built-in RAM, echoed every 512 bytes
+            0xa000..=0xbfff => {
+                if !self.ram_enable {
+                    return false;
+                }
+                ram[(addr & 0x01ff) as usize] = val & 0x0f;
+                return true;
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+        false
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![u8::from(self.ram_enable), self.rom_bank_no]
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        self.ram_enable = state[0] > 0;
+        self.rom_bank_no = state[1];
+    }
+}
@@ -0,0 +1,348 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{read_ram, read_rom, write_ram, Mapper};
+
+// MBC3 (max 2MB ROM and/or 32KB RAM and Timer).
+pub struct Mbc3 {
+    // Number of ROM banks
+    rom_banks: u16,
+    // RAM and timer enable
+    ram_enable: bool,
+    // ROM bank number (7 bits)
+    rom_bank_no: u8,
+    // RAM bank number or RTC register select
+    ram_bank_select: u8,
+    // Real time clock, if the cartridge has a timer
+    rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(rom_banks: u16, has_rtc: bool) -> Self {
+        Mbc3 {
+            rom_banks,
+            ram_enable: false,
+            rom_bank_no: 0,
+            ram_bank_select: 0,
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+        }
+    }
+
+    fn rom_bank_no(&self) -> u8 {
+        let bank_no = match self.rom_bank_no {
+            0 => 1,
+            n => n,
+        };
+
+        bank_no & (self.rom_banks - 1) as u8
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn read(&self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            // ROM bank 00
+            0x0000..=0x3fff => read_rom(rom, 0, addr),
+            // ROM bank 01-7f
+            0x4000..=0x7fff => read_rom(rom, self.rom_bank_no() as usize, addr),
+            // RAM bank 00-03 or RTC register
+            0xa000..=0xbfff => {
+                if !self.ram_enable {
+                    return 0xff;
+                }
+                match (self.ram_bank_select, &self.rtc) {
+                    (0x00..=0x03, _) => read_ram(ram, self.ram_bank_select as usize, addr),
+                    (0x08..=0x0c, Some(rtc)) => rtc.read(self.ram_bank_select),
+                    _ => 0xff,
+                }
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn write(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        match addr {
+            // RAM and timer enable
+            0x0000..=0x1fff => self.ram_enable = val & 0x0f == 0x0a,
+            // ROM bank number (7 bits)
+            0x2000..=0x3fff => self.rom_bank_no = val & 0x7f,
+            // RAM bank number or RTC register select
+            0x4000..=0x5fff => self.ram_bank_select = val,
+            // Latch clock data
+            0x6000..=0x7fff => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.latch(val);
+                }
+            }
+            // RAM bank 00-03 or RTC register
+            0xa000..=0xbfff => {
+                if !self.ram_enable {
+                    return false;
+                }
+                match (self.ram_bank_select, &mut self.rtc) {
+                    (0x00..=0x03, _) => {
+                        return write_ram(ram, self.ram_bank_select as usize, addr, val);
+                    }
+                    (0x08..=0x0c, Some(rtc)) => rtc.write(self.ram_bank_select, val),
+                    _ => (),
+                }
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+        false
+    }
+
+    fn update(&mut self, tick: u8) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.update(tick);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![u8::from(self.ram_enable), self.rom_bank_no, self.ram_bank_select];
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(&mut state);
+        }
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        self.ram_enable = state[0] > 0;
+        self.rom_bank_no = state[1];
+        self.ram_bank_select = state[2];
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(&state[3..]);
+        }
+    }
+
+    fn save_rtc(&self) -> Option<Vec<u8>> {
+        self.rtc.as_ref().map(|rtc| rtc.save_footer())
+    }
+
+    fn load_rtc(&mut self, data: &[u8]) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_footer(data);
+        }
+    }
+}
+
+// Number of clocks per second.
+const CLOCKS_PER_SEC: u32 = 4194304;
+
+// Size of the RTC footer appended to save files.
+pub const RTC_FOOTER_SIZE: usize = 48;
+// Size of the RTC footer with a 32-bit timestamp.
+pub const RTC_FOOTER_SIZE_32: usize = 44;
+
+// Returns the current UNIX time in seconds.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// MBC3 Real Time Clock.
+struct Rtc {
+    // Seconds (0-59)
+    seconds: u8,
+    // Minutes (0-59)
+    minutes: u8,
+    // Hours (0-23)
+    hours: u8,
+    // Day counter (0-511)
+    days: u16,
+    // Halt flag
+    halt: bool,
+    // Day counter carry flag
+    carry: bool,
+    // Latched register values (S, M, H, DL, DH)
+    latched: [u8; 5],
+    // Last value written to the latch register
+    latch_prev: u8,
+    // Elapsed clocks in current second
+    counter: u32,
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            latch_prev: 0xff,
+            counter: 0,
+        }
+    }
+
+    // Returns the current value of the register 0x08-0x0c.
+    fn register(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0a => self.hours,
+            0x0b => self.days as u8,
+            0x0c => {
+                ((self.days >> 8) as u8 & 0x01)
+                    | (u8::from(self.halt) << 6)
+                    | (u8::from(self.carry) << 7)
+            }
+            _ => unreachable!("Unexpected RTC register: 0x{:02x}", reg),
+        }
+    }
+
+    // Reads a latched register.
+    fn read(&self, reg: u8) -> u8 {
+        self.latched[(reg - 0x08) as usize]
+    }
+
+    // Writes a register.
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0x08 => {
+                self.seconds = val & 0x3f;
+                self.counter = 0;
+            }
+            0x09 => self.minutes = val & 0x3f,
+            0x0a => self.hours = val & 0x1f,
+            0x0b => self.days = (self.days & 0x100) | val as u16,
+            0x0c => {
+                self.days = (self.days & 0xff) | ((val as u16 & 0x01) << 8);
+                self.halt = val & 0x40 > 0;
+                self.carry = val & 0x80 > 0;
+            }
+            _ => unreachable!("Unexpected RTC register: 0x{:02x}", reg),
+        }
+        self.latched[(reg - 0x08) as usize] = self.register(reg);
+    }
+
+    // Latches the clock data on a 0x00 -> 0x01 write sequence.
+    fn latch(&mut self, val: u8) {
+        if self.latch_prev == 0x00 && val == 0x01 {
+            for reg in 0x08..=0x0c {
+                self.latched[(reg - 0x08) as usize] = self.register(reg);
+            }
+        }
+        self.latch_prev = val;
+    }
+
+    // Advances the clock by one second.
+    fn tick_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3f;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+
+        self.minutes = (self.minutes + 1) & 0x3f;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+
+        self.hours = (self.hours + 1) & 0x1f;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+
+        self.days += 1;
+        if self.days > 0x1ff {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    fn save_state(&self, state: &mut Vec<u8>) {
+        for reg in 0x08..=0x0c {
+            state.push(self.register(reg));
+        }
+        state.extend_from_slice(&self.latched);
+        state.push(self.latch_prev);
+        state.extend_from_slice(&self.counter.to_le_bytes());
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        for reg in 0x08..=0x0c {
+            self.write(reg, state[(reg - 0x08) as usize]);
+        }
+        self.latched.copy_from_slice(&state[5..10]);
+        self.latch_prev = state[10];
+        self.counter = u32::from_le_bytes([state[11], state[12], state[13], state[14]]);
+    }
+
+    // Serializes the clock in the VBA/BGB save file footer layout.
+    //
+    // The footer is 48 bytes: current S, M, H, DL, DH and latched S, M, H,
+    // DL, DH as 32-bit little endian words, then a 64-bit UNIX timestamp.
+    fn save_footer(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for reg in 0x08..=0x0c {
+            footer.extend_from_slice(&(self.register(reg) as u32).to_le_bytes());
+        }
+        for val in self.latched {
+            footer.extend_from_slice(&(val as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&unix_time().to_le_bytes());
+        footer
+    }
+
+    // Restores the clock from a save file footer, and advances it by the
+    // real time elapsed since the footer was written.
+    //
+    // Accepts both the 48-byte footer and the older 44-byte one with a
+    // 32-bit timestamp.
+    fn load_footer(&mut self, footer: &[u8]) {
+        // Registers are stored as 32-bit words, only the low byte is used
+        let word = |i: usize| footer[i * 4];
+
+        for reg in 0x08..=0x0c {
+            self.write(reg, word((reg - 0x08) as usize));
+        }
+        for i in 0..5 {
+            self.latched[i] = word(i + 5);
+        }
+
+        let timestamp = match footer.len() {
+            RTC_FOOTER_SIZE => u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            _ => u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64,
+        };
+        if !self.halt {
+            self.advance(unix_time().saturating_sub(timestamp));
+        }
+    }
+
+    // Advances the clock by a number of seconds.
+    fn advance(&mut self, secs: u64) {
+        let total = self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 60 * 60
+            + self.days as u64 * 24 * 60 * 60
+            + secs;
+
+        let days = total / (24 * 60 * 60);
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / (60 * 60) % 24) as u8;
+        self.days = (days & 0x1ff) as u16;
+        if days > 0x1ff {
+            self.carry = true;
+        }
+    }
+
+    fn update(&mut self, tick: u8) {
+        if self.halt {
+            return;
+        }
+
+        self.counter += tick as u32;
+
+        while self.counter >= CLOCKS_PER_SEC {
+            self.counter -= CLOCKS_PER_SEC;
+            self.tick_second();
+        }
+    }
+}
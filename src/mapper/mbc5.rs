@@ -0,0 +1,101 @@
+use super::{read_ram, read_rom, write_ram, Mapper};
+
+// MBC5 (max 8MB ROM and/or 128KB RAM).
+pub struct Mbc5 {
+    // Number of ROM banks
+    rom_banks: u16,
+    // Bit 3 of the RAM bank register drives the rumble motor
+    has_rumble: bool,
+    ram_enable: bool,
+    // ROM bank number (lower 8 bits)
+    bank_no_lower: u8,
+    // ROM bank number (9th bit)
+    bank_no_upper: u8,
+    // RAM bank number
+    ram_bank_no: u8,
+}
+
+impl Mbc5 {
+    pub fn new(rom_banks: u16, has_rumble: bool) -> Self {
+        Mbc5 {
+            rom_banks,
+            has_rumble,
+            ram_enable: false,
+            bank_no_lower: 1,
+            bank_no_upper: 0,
+            ram_bank_no: 0,
+        }
+    }
+
+    fn rom_bank_no(&self) -> u16 {
+        let bank_no = (self.bank_no_upper as u16) << 8 | self.bank_no_lower as u16;
+
+        bank_no & (self.rom_banks - 1)
+    }
+
+    fn ram_bank_no(&self) -> u8 {
+        if self.has_rumble {
+            self.ram_bank_no & 0x07
+        } else {
+            self.ram_bank_no
+        }
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn read(&self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            // ROM bank 00
+            0x0000..=0x3fff => read_rom(rom, 0, addr),
+            // ROM bank 000-1ff
+            0x4000..=0x7fff => read_rom(rom, self.rom_bank_no() as usize, addr),
+            // RAM bank 00-0f
+            0xa000..=0xbfff => {
+                if !self.ram_enable {
+                    return 0xff;
+                }
+                read_ram(ram, self.ram_bank_no() as usize, addr)
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn write(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        match addr {
+            // RAM enable
+            0x0000..=0x1fff => self.ram_enable = val == 0x0a,
+            // ROM bank number (lower 8 bits)
+            0x2000..=0x2fff => self.bank_no_lower = val,
+            // ROM bank number (9th bit)
+            0x3000..=0x3fff => self.bank_no_upper = val & 0x01,
+            // RAM bank number
+            0x4000..=0x5fff => self.ram_bank_no = val & 0x0f,
+            0x6000..=0x7fff => (),
+            // RAM bank 00-0f
+            0xa000..=0xbfff => {
+                if !self.ram_enable {
+                    return false;
+                }
+                return write_ram(ram, self.ram_bank_no() as usize, addr, val);
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+        false
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![
+            u8::from(self.ram_enable),
+            self.bank_no_lower,
+            self.bank_no_upper,
+            self.ram_bank_no,
+        ]
+    }
+
+    fn load_state(&mut self, state: &[u8]) {
+        self.ram_enable = state[0] > 0;
+        self.bank_no_lower = state[1];
+        self.bank_no_upper = state[2];
+        self.ram_bank_no = state[3];
+    }
+}
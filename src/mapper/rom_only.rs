@@ -0,0 +1,39 @@
+use super::{read_ram, read_rom, write_ram, Mapper};
+
+// Cartridge without memory bank controller (32KB ROM, optional 8KB RAM).
+pub struct RomOnly {}
+
+impl RomOnly {
+    pub fn new() -> Self {
+        RomOnly {}
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read(&self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+        match addr {
+            // ROM bank 00
+            0x0000..=0x3fff => read_rom(rom, 0, addr),
+            // ROM bank 01
+            0x4000..=0x7fff => read_rom(rom, 1, addr),
+            // RAM
+            0xa000..=0xbfff => read_ram(ram, 0, addr),
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn write(&mut self, ram: &mut [u8], addr: u16, val: u8) -> bool {
+        match addr {
+            0x0000..=0x7fff => false,
+            // RAM
+            0xa000..=0xbfff => write_ram(ram, 0, addr, val),
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, _state: &[u8]) {}
+}
@@ -1,54 +1,451 @@
 
-use crate::cartridge::Cartridge;
+use std::cell::Cell;
+
+use crate::apu::{APU, DEFAULT_SAMPLE_RATE};
+use crate::cartridge::{Cartridge, CartridgeError};
 use crate::bus::Bus;
-use crate::ppu::PPU;
+use crate::ppu::{Renderer, PPU};
 use crate::joypad::Joypad;
+use crate::serial::Serial;
+use crate::state::{Sections, StateError, StateWriter};
 use crate::timer::Timer;
 
+// OAM DMA transfer in progress.
+struct OamDma {
+    // Source address of the next byte
+    src: u16,
+    // Index of the next byte in OAM
+    index: u8,
+    // Elapsed clocks, negative during the write to 0xff46 and the startup
+    // delay
+    counter: i16,
+    // Byte on the source bus, seen by conflicting CPU reads
+    value: u8,
+}
+
+// Memory watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    // Watched addresses, inclusive
+    pub start: u16,
+    pub end: u16,
+    // Stop on reads and on writes
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    // Returns true if the access triggers the watchpoint.
+    pub fn matches(&self, addr: u16, write: bool) -> bool {
+        (self.start..=self.end).contains(&addr) && if write { self.write } else { self.read }
+    }
+}
+
+// Memory access which triggered a watchpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub val: u8,
+    pub write: bool,
+}
+
 // Memory Management Unit
 pub struct MMU {
     pub cartridge: Cartridge,
-    ram: [u8; 0x2000],
+    // WRAM bank 0-7 (banks 2-7 are CGB only)
+    ram: [u8; 0x8000],
+    // WRAM Bank
+    svbk: u8,
     hram: [u8; 0x7f],
     pub joypad: Joypad,
     timer: Timer,
+    pub serial: Serial,
     pub ppu: PPU,
+    pub apu: APU,
     /// Interrupt flag
     pub int_flag: u8,
     /// Interrupt enable
     pub int_enable: u8,
+    /// Boot ROM, mapped at 0x0000-0x00ff until 0xff50 is written
+    boot_rom: Option<Vec<u8>>,
+    /// CGB mode
+    cgb: bool,
+    /// Prepare speed switch
+    key1: u8,
+    /// Double speed mode
+    double_speed: bool,
+    /// VRAM DMA source
+    hdma_src: u16,
+    /// VRAM DMA destination
+    hdma_dst: u16,
+    /// Remaining 16-byte blocks of H-Blank DMA
+    hdma_len: u8,
+    /// H-Blank DMA in progress
+    hdma_active: bool,
+    /// OAM DMA in progress
+    dma: Option<OamDma>,
+    /// Clocks of the current CPU step already run by OAM DMA
+    dma_cycle: u8,
+    /// Memory watchpoints checked on CPU reads and writes
+    pub(crate) watchpoints: Vec<Watchpoint>,
+    /// First access which hit a watchpoint since the last check
+    watch_hit: Cell<Option<WatchHit>>,
 }
 
+// IO register values left by the DMG boot ROM.
+const POST_BOOT_IO: [(u16, u8); 29] = [
+    (0xff00, 0xcf), // P1
+    (0xff05, 0x00), // TIMA
+    (0xff06, 0x00), // TMA
+    (0xff07, 0xf8), // TAC
+    (0xff26, 0xf1), // NR52 (sound must be on before other sound registers)
+    (0xff10, 0x80), // NR10
+    (0xff11, 0xbf), // NR11
+    (0xff12, 0xf3), // NR12
+    (0xff13, 0xff), // NR13
+    (0xff14, 0xbf), // NR14 (channel 1 is still playing the boot sound)
+    (0xff16, 0x3f), // NR21
+    (0xff17, 0x00), // NR22
+    (0xff18, 0xff), // NR23
+    (0xff19, 0x3f), // NR24
+    (0xff1a, 0x7f), // NR30
+    (0xff1b, 0xff), // NR31
+    (0xff1c, 0x9f), // NR32
+    (0xff1d, 0xff), // NR33
+    (0xff1e, 0x3f), // NR34
+    (0xff20, 0xff), // NR41
+    (0xff21, 0x00), // NR42
+    (0xff22, 0x00), // NR43
+    (0xff23, 0x3f), // NR44
+    (0xff24, 0x77), // NR50
+    (0xff25, 0xf3), // NR51
+    (0xff40, 0x91), // LCDC
+    (0xff42, 0x00), // SCY
+    (0xff43, 0x00), // SCX
+    (0xff47, 0xfc), // BGP
+];
+
 impl MMU {
-    pub fn new(rom_name: &str) -> Self {
-        MMU {
-            cartridge: Cartridge::new(rom_name),
-            ram: [0; 0x2000],
+    // Creates an MMU with the cartridge loaded from a ROM file. CGB mode is
+    // selected from the cartridge header unless overridden.
+    pub fn new(
+        rom_name: &str,
+        strict: bool,
+        boot_rom: Option<Vec<u8>>,
+        cgb: Option<bool>,
+        renderer: Renderer,
+    ) -> Result<Self, CartridgeError> {
+        let cartridge = Cartridge::new(rom_name, strict)?;
+
+        Ok(MMU::with_cartridge(cartridge, boot_rom, cgb, renderer))
+    }
+
+    // Creates an MMU with a loaded cartridge.
+    pub fn with_cartridge(
+        cartridge: Cartridge,
+        boot_rom: Option<Vec<u8>>,
+        cgb: Option<bool>,
+        renderer: Renderer,
+    ) -> Self {
+        let cgb = cgb.unwrap_or(cartridge.cgb_flag);
+
+        let mut mmu = MMU {
+            cartridge,
+            ram: [0; 0x8000],
+            svbk: 0,
             hram: [0; 0x7f],
             joypad: Joypad::new(),
-            ppu: PPU::new(),
+            ppu: PPU::new(cgb, renderer),
             timer: Timer::new(),
+            serial: Serial::new(),
+            apu: APU::new(DEFAULT_SAMPLE_RATE),
             int_flag: 0,
             int_enable: 0,
+            boot_rom: None,
+            cgb,
+            key1: 0,
+            double_speed: false,
+            hdma_src: 0,
+            hdma_dst: 0,
+            hdma_len: 0,
+            hdma_active: false,
+            dma: None,
+            dma_cycle: 0,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+        };
+
+        if boot_rom.is_some() {
+            // LCD is off at power on
+            mmu.write(0xff40, 0x00);
+            mmu.boot_rom = boot_rom;
+        } else {
+            mmu.init_post_boot();
         }
+
+        mmu
     }
 
-    fn do_dma(&mut self, val: u8) {
-        if val < 0x80 || 0xdf < val {
-            panic!("Invalid DMA source address")
+    // Initializes IO registers to the values left by the boot ROM.
+    fn init_post_boot(&mut self) {
+        for &(addr, val) in POST_BOOT_IO.iter() {
+            self.write(addr, val);
         }
 
-        let src_base = (val as u16) << 8;
-        let dst_base = 0xfe00;
+        if self.cgb {
+            // The CGB boot ROM leaves all colors white
+            self.write(0xff68, 0x80);
+            self.write(0xff6a, 0x80);
+            for _ in 0..0x40 {
+                self.write(0xff69, 0xff);
+                self.write(0xff6b, 0xff);
+            }
+        }
 
-        for i in 0..0xa0 {
-            let tmp = self.read(src_base | i);
-            self.write(dst_base | i, tmp);
+        self.ppu.init_post_boot();
+        self.timer.set_counter(0xabcc);
+        self.int_flag = 0x01;
+    }
+
+    // Returns true while the boot ROM is mapped.
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    // Returns true in CGB mode.
+    pub fn cgb(&self) -> bool {
+        self.cgb
+    }
+
+    // Returns true in CGB double speed mode.
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Switches CPU speed if prepared through KEY1. Returns true if switched.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb || self.key1 & 0x1 == 0 {
+            return false;
+        }
+
+        self.key1 = 0;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    // Serializes memory and registers, and the sections of all components.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.section(b"MMU ", |w| {
+            w.bytes(&self.ram);
+            w.u8(self.svbk);
+            w.bytes(&self.hram);
+            w.u8(self.int_flag);
+            w.u8(self.int_enable);
+            w.bool(self.boot_rom.is_some());
+            w.bool(self.cgb);
+            w.u8(self.key1);
+            w.bool(self.double_speed);
+            w.u16(self.hdma_src);
+            w.u16(self.hdma_dst);
+            w.u8(self.hdma_len);
+            w.bool(self.hdma_active);
+            w.bool(self.dma.is_some());
+            if let Some(dma) = &self.dma {
+                w.u16(dma.src);
+                w.u8(dma.index);
+                w.u16(dma.counter as u16);
+                w.u8(dma.value);
+            }
+        });
+
+        w.section(b"CART", |w| self.cartridge.save_state(w));
+        w.section(b"PPU ", |w| self.ppu.save_state(w));
+        w.section(b"APU ", |w| self.apu.save_state(w));
+        w.section(b"TIMR", |w| self.timer.save_state(w));
+        w.section(b"JOYP", |w| self.joypad.save_state(w));
+        w.section(b"SERL", |w| self.serial.save_state(w));
+    }
+
+    pub(crate) fn load_state(&mut self, sections: &Sections) -> Result<(), StateError> {
+        let mut r = sections.reader(b"MMU ")?;
+
+        r.bytes(&mut self.ram)?;
+        self.svbk = r.u8()?;
+        r.bytes(&mut self.hram)?;
+        self.int_flag = r.u8()?;
+        self.int_enable = r.u8()?;
+        if r.bool()? {
+            if self.boot_rom.is_none() {
+                return Err(StateError::Invalid("saved while running the boot ROM"));
+            }
+        } else {
+            self.boot_rom = None;
+        }
+        self.cgb = r.bool()?;
+        self.key1 = r.u8()?;
+        self.double_speed = r.bool()?;
+        self.hdma_src = r.u16()?;
+        self.hdma_dst = r.u16()?;
+        self.hdma_len = r.u8()?;
+        self.hdma_active = r.bool()?;
+        self.dma = if r.bool()? {
+            Some(OamDma {
+                src: r.u16()?,
+                index: r.u8()?,
+                counter: r.u16()? as i16,
+                value: r.u8()?,
+            })
+        } else {
+            None
+        };
+
+        self.cartridge.load_state(&mut sections.reader(b"CART")?)?;
+        self.ppu.load_state(&mut sections.reader(b"PPU ")?)?;
+        self.apu.load_state(&mut sections.reader(b"APU ")?)?;
+        self.timer.load_state(&mut sections.reader(b"TIMR")?)?;
+        self.joypad.load_state(&mut sections.reader(b"JOYP")?)?;
+        self.serial.load_state(&mut sections.reader(b"SERL")?)
+    }
+
+    // Returns true if the boot ROM overlays the given address.
+    fn boot_rom_addr(&self, addr: u16) -> bool {
+        match &self.boot_rom {
+            // The CGB boot ROM is split around the cartridge header
+            Some(boot_rom) => {
+                addr < 0x100 || ((0x200..0x900).contains(&addr) && (addr as usize) < boot_rom.len())
+            }
+            None => false,
+        }
+    }
+
+    // Returns the index into WRAM for an address in 0xc000-0xdfff.
+    fn ram_index(&self, addr: u16) -> usize {
+        if addr & 0x1000 == 0 {
+            (addr & 0x0fff) as usize
+        } else {
+            // Bank 0 selects bank 1
+            let bank = if self.cgb { (self.svbk & 0x7).max(1) } else { 1 };
+            (bank as usize) << 12 | (addr & 0x0fff) as usize
+        }
+    }
+
+    // Starts a general purpose or H-Blank VRAM DMA.
+    fn start_hdma(&mut self, val: u8) {
+        if self.hdma_active && val & 0x80 == 0 {
+            // Stop the H-Blank DMA in progress
+            self.hdma_active = false;
+            return;
+        }
+
+        self.hdma_len = (val & 0x7f) + 1;
+
+        if val & 0x80 > 0 {
+            self.hdma_active = true;
+        } else {
+            while self.hdma_len > 0 {
+                self.hdma_transfer_block();
+            }
+        }
+    }
+
+    // Copies one 16-byte block of VRAM DMA. The transfer is not a CPU access,
+    // so it doesn't trigger watchpoints or OAM DMA bus conflicts.
+    fn hdma_transfer_block(&mut self) {
+        for _ in 0..0x10 {
+            let val = self.read_bus(self.hdma_src);
+            self.write_bus(0x8000 | (self.hdma_dst & 0x1fff), val);
+            self.hdma_src = self.hdma_src.wrapping_add(1);
+            self.hdma_dst = self.hdma_dst.wrapping_add(1);
+        }
+
+        self.hdma_len -= 1;
+        if self.hdma_len == 0 {
+            self.hdma_active = false;
+        }
+    }
+
+    // Starts OAM DMA from the given source page.
+    fn start_dma(&mut self, val: u8) {
+        self.ppu.write(0xff46, val);
+
+        self.dma = Some(OamDma {
+            src: (val as u16) << 8,
+            index: 0,
+            // Transfer starts after the machine cycle of this write and one
+            // more
+            counter: -8,
+            value: 0xff,
+        });
+    }
+
+    // Runs OAM DMA up to `cycle` clocks into the current CPU step. Called
+    // before each memory access, so bus conflicts are checked against the
+    // transfer state at the time of the access.
+    pub(crate) fn sync_dma(&mut self, cycle: u8) {
+        if cycle > self.dma_cycle {
+            self.update_dma(cycle - self.dma_cycle);
+            self.dma_cycle = cycle;
+        }
+    }
+
+    // Transfers one byte per machine cycle of a running OAM DMA.
+    fn update_dma(&mut self, cycle: u8) {
+        let mut dma = match self.dma.take() {
+            Some(dma) => dma,
+            None => return,
+        };
+
+        dma.counter += cycle as i16;
+        while dma.counter >= 4 {
+            dma.counter -= 4;
+
+            // Sources above 0xdfff read the echo of WRAM
+            let src = if dma.src >= 0xe000 { dma.src - 0x2000 } else { dma.src };
+            dma.value = self.read_bus(src);
+            self.ppu.write_oam_dma(dma.index, dma.value);
+
+            dma.src += 1;
+            dma.index += 1;
+            if dma.index >= 0xa0 {
+                return;
+            }
+        }
+
+        self.dma = Some(dma);
+    }
+
+    // Returns true if the CPU can't access the address during OAM DMA. Only
+    // HRAM and IO registers, and the bus not used by the DMA source, are
+    // accessible.
+    fn dma_conflict(&self, addr: u16) -> bool {
+        // VRAM is on a separate bus from ROM and WRAM
+        let vram_bus = |addr: u16| (0x8000..=0x9fff).contains(&addr);
+
+        match &self.dma {
+            Some(dma) if dma.counter >= 0 => match addr {
+                0xfe00..=0xfeff => true,
+                0xff00..=0xffff => false,
+                _ => vram_bus(addr) == vram_bus(dma.src),
+            },
+            _ => false,
         }
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, val, true);
+        }
+        if self.dma_conflict(addr) {
+            return;
+        }
+
+        self.write_bus(addr, val);
+    }
+
+    fn write_bus(&mut self, addr: u16, val: u8) {
         match addr {
+            // Boot ROM disable
+            0xff50 if val != 0 => self.boot_rom = None,
             // ROM
             0x0000..=0x7fff => self.cartridge.write(addr, val),
             // VRAM
@@ -56,21 +453,34 @@ impl MMU {
             // External RAM
             0xa000..=0xbfff => self.cartridge.write(addr, val),
             // RAM
-            0xc000..=0xdfff => self.ram[(addr & 0x1fff) as usize] = val,
+            0xc000..=0xdfff => self.ram[self.ram_index(addr)] = val,
             // Echo RAM
-            0xe000..=0xfdff => self.ram[((addr - 0x2000) & 0x1fff) as usize] = val,
+            0xe000..=0xfdff => self.ram[self.ram_index(addr - 0x2000)] = val,
             // OAM
             0xfe00..=0xfe9f => self.ppu.write(addr, val),
             // Joypad
             0xff00 => self.joypad.write(addr, val),
+            // Serial
+            0xff01..=0xff02 => self.serial.write(addr, val),
             // Timer
             0xff04..=0xff07 => self.timer.write(addr, val),
             // Interrupt flag
             0xff0f => self.int_flag = val,
+            // APU
+            0xff10..=0xff3f => self.apu.write(addr, val),
             // PPU
             0xff40..=0xff45 | 0xff47..=0xff4b => self.ppu.write(addr, val),
             // OAM DMA
-            0xff46 => self.do_dma(val),
+            0xff46 => self.start_dma(val),
+            // CGB registers
+            0xff4d if self.cgb => self.key1 = val & 0x1,
+            0xff4f | 0xff68..=0xff6b if self.cgb => self.ppu.write(addr, val),
+            0xff51 if self.cgb => self.hdma_src = (self.hdma_src & 0x00ff) | (val as u16) << 8,
+            0xff52 if self.cgb => self.hdma_src = (self.hdma_src & 0xff00) | (val & 0xf0) as u16,
+            0xff53 if self.cgb => self.hdma_dst = (self.hdma_dst & 0x00ff) | ((val & 0x1f) as u16) << 8,
+            0xff54 if self.cgb => self.hdma_dst = (self.hdma_dst & 0xff00) | (val & 0xf0) as u16,
+            0xff55 if self.cgb => self.start_hdma(val),
+            0xff70 if self.cgb => self.svbk = val & 0x7,
             // HRAM
             0xff80..=0xfffe => self.hram[(addr & 0x7f) as usize] = val,
             // Interrupt enable
@@ -80,6 +490,58 @@ impl MMU {
     }
 
     pub fn read(&self, addr: u16) -> u8 {
+        let val = self.fetch(addr);
+
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, val, false);
+        }
+        val
+    }
+
+    // Reads an instruction byte. Unlike `read`, it doesn't trigger read
+    // watchpoints, which are meant for data.
+    pub fn fetch(&self, addr: u16) -> u8 {
+        if self.dma_conflict(addr) {
+            match (addr, &self.dma) {
+                (0x0000..=0xfdff, Some(dma)) => dma.value,
+                _ => 0xff,
+            }
+        } else {
+            self.read_bus(addr)
+        }
+    }
+
+    // Reads memory without side effects, bypassing watchpoints and OAM DMA.
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.read_bus(addr)
+    }
+
+    // Writes memory bypassing watchpoints and OAM DMA.
+    pub fn poke(&mut self, addr: u16, val: u8) {
+        self.write_bus(addr, val);
+    }
+
+    // Records the access if it hits a watchpoint.
+    fn check_watchpoints(&self, addr: u16, val: u8, write: bool) {
+        let hit = self.watchpoints.iter().any(|w| w.matches(addr, write));
+        if hit && self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(WatchHit { addr, val, write }));
+        }
+    }
+
+    // Returns and clears the access which hit a watchpoint.
+    pub(crate) fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn read_bus(&self, addr: u16) -> u8 {
+        // Boot ROM
+        if self.boot_rom_addr(addr) {
+            if let Some(boot_rom) = &self.boot_rom {
+                return boot_rom[addr as usize];
+            }
+        }
+
         match addr {
             // ROM
             0x0000..=0x7fff => self.cartridge.read(addr),
@@ -88,19 +550,28 @@ impl MMU {
             // External RAM
             0xa000..=0xbfff => self.cartridge.read(addr),
             // RAM
-            0xc000..=0xdfff => self.ram[(addr & 0x1fff) as usize],
+            0xc000..=0xdfff => self.ram[self.ram_index(addr)],
             // Echo RAM
-            0xe000..=0xfdff => self.ram[((addr - 0x2000) & 0x1fff) as usize],
+            0xe000..=0xfdff => self.ram[self.ram_index(addr - 0x2000)],
             // OAM
             0xfe00..=0xfe9f => self.ppu.read(addr),
             // Joypad
             0xff00 => self.joypad.read(addr),
+            // Serial
+            0xff01..=0xff02 => self.serial.read(addr),
             // Timer
             0xff04..=0xff07 => self.timer.read(addr),
             // Interrupt flag
-            0xff0f => self.int_flag,
+            0xff0f => self.int_flag | 0xe0,
+            // APU
+            0xff10..=0xff3f => self.apu.read(addr),
             // PPU
-            0xff40..=0xff45 | 0xff47..=0xff4b => self.ppu.read(addr),
+            0xff40..=0xff4b => self.ppu.read(addr),
+            // CGB registers
+            0xff4d if self.cgb => 0x7e | u8::from(self.double_speed) << 7 | self.key1,
+            0xff4f | 0xff68..=0xff6b if self.cgb => self.ppu.read(addr),
+            0xff55 if self.cgb => u8::from(!self.hdma_active) << 7 | self.hdma_len.wrapping_sub(1) & 0x7f,
+            0xff70 if self.cgb => 0xf8 | self.svbk,
             // HRAM
             0xff80..=0xfffe => self.hram[(addr & 0x7f) as usize],
             // Interrupt enable
@@ -110,10 +581,25 @@ impl MMU {
     }
 
     pub fn update(&mut self, cycle: u8) {
-        self.cartridge.update(cycle);
-        self.ppu.update(cycle);
+        // In double speed mode, only the timer and serial port run faster
+        let normal_cycle = if self.double_speed { cycle / 2 } else { cycle };
+
+        self.cartridge.update(normal_cycle);
+        self.ppu.update(normal_cycle);
         self.timer.update(cycle);
+        self.serial.update(cycle);
+        self.serial.sync(normal_cycle);
         self.joypad.update(cycle);
+        self.apu.update(normal_cycle);
+        self.sync_dma(cycle);
+        self.dma_cycle = 0;
+
+        if self.ppu.hblank {
+            self.ppu.hblank = false;
+            if self.hdma_active {
+                self.hdma_transfer_block();
+            }
+        }
 
         if self.ppu.irq_vblank {
             self.int_flag |= 0x1;
@@ -130,9 +616,132 @@ impl MMU {
             self.timer.irq = false;
         }
 
+        if self.serial.irq {
+            self.int_flag |= 0x8;
+            self.serial.irq = false;
+        }
+
         if self.joypad.irq {
             self.int_flag |= 0x10;
             self.joypad.irq = false;
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::tests::build_rom;
+
+    // Creates an MMU with the LCD off, so OAM is always accessible, and WRAM
+    // from 0xc000 filled with 1, 2, 3...
+    fn mmu(cgb: bool) -> MMU {
+        let cartridge = Cartridge::from_rom(build_rom(0x00, 0x00, 0x00), true).unwrap();
+        let mut mmu = MMU::with_cartridge(cartridge, None, Some(cgb), Renderer::Scanline);
+        mmu.poke(0xff40, 0x00);
+        for i in 0..0xa0 {
+            mmu.poke(0xc000 + i, i as u8 + 1);
+        }
+        mmu
+    }
+
+    // Starts OAM DMA from 0xc000 like LDH (0x46),A, which writes in its
+    // third machine cycle.
+    fn start_dma(mmu: &mut MMU) {
+        mmu.sync_dma(8);
+        mmu.write(0xff46, 0xc0);
+        mmu.update(12);
+    }
+
+    #[test]
+    fn dma_starts_after_write() {
+        let mut mmu = mmu(false);
+        start_dma(&mut mmu);
+
+        // Startup delay
+        assert_eq!(mmu.peek(0xfe00), 0x00);
+        assert!(!mmu.dma_conflict(0xc000));
+        mmu.update(4);
+
+        // One byte per machine cycle
+        assert!(mmu.dma_conflict(0xc000));
+        mmu.update(4);
+        assert_eq!(mmu.peek(0xfe00), 0x01);
+        assert_eq!(mmu.peek(0xfe01), 0x00);
+
+        for _ in 1..0x9f {
+            mmu.update(4);
+        }
+        assert!(mmu.dma_conflict(0xc000));
+        assert_eq!(mmu.peek(0xfe9f), 0x00);
+
+        mmu.update(4);
+        assert!(!mmu.dma_conflict(0xc000));
+        for i in 0..0xa0 {
+            assert_eq!(mmu.peek(0xfe00 + i), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn dma_advances_per_access() {
+        let mut mmu = mmu(false);
+        start_dma(&mut mmu);
+
+        // A read 8 clocks into the next instruction sees the transfer running
+        mmu.sync_dma(8);
+        assert!(mmu.dma_conflict(0xc000));
+        mmu.update(12);
+        assert_eq!(mmu.peek(0xfe00), 0x01);
+        assert_eq!(mmu.peek(0xfe01), 0x02);
+        assert_eq!(mmu.peek(0xfe02), 0x00);
+    }
+
+    #[test]
+    fn only_hram_is_accessible_during_dma() {
+        let mut mmu = mmu(false);
+        mmu.poke(0xff80, 0x42);
+        mmu.poke(0x8000, 0x24);
+        start_dma(&mut mmu);
+        mmu.update(8);
+
+        // The external bus returns the byte being transferred
+        assert_eq!(mmu.read(0xc050), 0x01);
+        assert_eq!(mmu.read(0x0000), 0x01);
+        assert_eq!(mmu.read(0xfe00), 0xff);
+        mmu.write(0xc050, 0x00);
+        assert_eq!(mmu.peek(0xc050), 0x51);
+
+        // HRAM and IO registers are not on the bus, nor is VRAM when copying
+        // from WRAM
+        assert_eq!(mmu.read(0xff80), 0x42);
+        mmu.write(0xff81, 0x43);
+        assert_eq!(mmu.peek(0xff81), 0x43);
+        assert_eq!(mmu.read(0x8000), 0x24);
+        assert_eq!(mmu.read(0xff40), 0x00);
+    }
+
+    #[test]
+    fn hdma_ignores_watchpoints_and_dma_conflicts() {
+        let mut mmu = mmu(true);
+        mmu.watchpoints.push(Watchpoint {
+            start: 0x0000,
+            end: 0xffff,
+            read: true,
+            write: true,
+        });
+        start_dma(&mut mmu);
+        mmu.update(8);
+        mmu.take_watch_hit();
+
+        // General purpose DMA of 16 bytes from 0xc000 to 0x8000
+        mmu.poke(0xff51, 0xc0);
+        mmu.poke(0xff52, 0x00);
+        mmu.poke(0xff53, 0x00);
+        mmu.poke(0xff54, 0x00);
+        mmu.poke(0xff55, 0x00);
+
+        assert!(mmu.take_watch_hit().is_none());
+        for i in 0..0x10 {
+            assert_eq!(mmu.peek(0x8000 + i), i as u8 + 1);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs;
+
+// RGB color of a pixel.
+pub type Rgb = [u8; 3];
+
+// Colors used to display the four DMG shades of BG, OBP0 and OBP1.
+#[derive(Clone, Debug)]
+pub struct Palette {
+    pub name: String,
+    pub bg: [Rgb; 4],
+    pub obj0: [Rgb; 4],
+    pub obj1: [Rgb; 4],
+}
+
+impl Palette {
+    // Creates a palette using the same colors for BG and sprites.
+    fn uniform(name: &str, colors: [u32; 4]) -> Self {
+        let colors = colors.map(rgb);
+
+        Palette {
+            name: name.to_string(),
+            bg: colors,
+            obj0: colors,
+            obj1: colors,
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::uniform("Grey", [0xffffff, 0xaaaaaa, 0x555555, 0x000000])
+    }
+}
+
+// Converts 0xRRGGBB to RGB.
+fn rgb(color: u32) -> Rgb {
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8]
+}
+
+// Returns the built-in palettes.
+pub fn presets() -> Vec<Palette> {
+    vec![
+        Palette::default(),
+        Palette::uniform("DMG green", [0x9bbc0f, 0x8bac0f, 0x306230, 0x0f380f]),
+        Palette::uniform("Pocket grey", [0xc4cfa1, 0x8b956d, 0x4d533c, 0x1f1f1f]),
+        Palette::uniform("Light", [0x00b581, 0x009a71, 0x00694a, 0x004f3b]),
+    ]
+}
+
+// Loads user palettes from a config file.
+//
+// Each palette starts with its name in brackets, followed by the colors of
+// BG, OBP0 and OBP1 as four hex RGB values from lightest to darkest. OBP0
+// and OBP1 default to the BG colors.
+//
+//   [Autumn]
+//   bg = fff6d3 f9a875 eb6b6f 7c3f58
+//   obp0 = ffffff f9a875 eb6b6f 000000
+pub fn load(fname: &str) -> Result<Vec<Palette>, String> {
+    let config = fs::read_to_string(fname).map_err(|e| e.to_string())?;
+    parse(&config)
+}
+
+// Parses the palette config file format.
+fn parse(config: &str) -> Result<Vec<Palette>, String> {
+    let mut palettes: Vec<Palette> = Vec::new();
+    // Whether OBP0 and OBP1 have been set for the current palette
+    let mut obj_set = (false, false);
+
+    for (line_no, line) in config.lines().enumerate() {
+        let line = line.trim();
+        let err = |msg: &str| format!("line {}: {}", line_no + 1, msg);
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            palettes.push(Palette {
+                name: name.trim().to_string(),
+                ..Palette::default()
+            });
+            obj_set = (false, false);
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or_else(|| err("expected key = colors"))?;
+        let palette = palettes.last_mut().ok_or_else(|| err("colors outside of a palette"))?;
+        let colors = parse_colors(value).ok_or_else(|| err("expected four hex colors"))?;
+
+        match key.trim() {
+            "bg" => {
+                palette.bg = colors;
+                if !obj_set.0 {
+                    palette.obj0 = colors;
+                }
+                if !obj_set.1 {
+                    palette.obj1 = colors;
+                }
+            }
+            "obp0" => {
+                palette.obj0 = colors;
+                obj_set.0 = true;
+            }
+            "obp1" => {
+                palette.obj1 = colors;
+                obj_set.1 = true;
+            }
+            key => return Err(err(&format!("unknown key {}", key))),
+        }
+    }
+
+    Ok(palettes)
+}
+
+// Parses four hex RGB colors.
+fn parse_colors(value: &str) -> Option<[Rgb; 4]> {
+    let mut colors = [[0; 3]; 4];
+    let mut values = value.split_whitespace();
+
+    for color in colors.iter_mut() {
+        let hex = values.next()?;
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if hex.len() != 6 {
+            return None;
+        }
+        *color = rgb(u32::from_str_radix(hex, 16).ok()?);
+    }
+
+    match values.next() {
+        Some(_) => None,
+        None => Some(colors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_palettes() {
+        let config = "\
+# User palettes
+[Autumn]
+bg = fff6d3 f9a875 eb6b6f 7c3f58
+obp0 = #ffffff #f9a875 #eb6b6f #000000
+
+[ Night ]
+obp1 = 000000 111111 222222 333333
+bg = 102030 405060 708090 a0b0c0
+";
+        let palettes = parse(config).unwrap();
+        assert_eq!(palettes.len(), 2);
+
+        let autumn = &palettes[0];
+        assert_eq!(autumn.name, "Autumn");
+        assert_eq!(autumn.bg[0], [0xff, 0xf6, 0xd3]);
+        assert_eq!(autumn.bg[3], [0x7c, 0x3f, 0x58]);
+        assert_eq!(autumn.obj0[0], [0xff, 0xff, 0xff]);
+        // OBP1 defaults to the BG colors
+        assert_eq!(autumn.obj1, autumn.bg);
+
+        // BG doesn't override OBP1 given before it
+        let night = &palettes[1];
+        assert_eq!(night.name, "Night");
+        assert_eq!(night.bg[1], [0x40, 0x50, 0x60]);
+        assert_eq!(night.obj0, night.bg);
+        assert_eq!(night.obj1[3], [0x33, 0x33, 0x33]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("bg = 000000 111111 222222 333333", "line 1: colors outside of a palette"),
+            ("[A]\nbg = 000000 111111 222222", "line 2: expected four hex colors"),
+            ("[A]\nbg = 000000 111111 222222 333333 444444", "line 2: expected four hex colors"),
+            ("[A]\n\nbg = 00000g 111111 222222 333333", "line 3: expected four hex colors"),
+            ("[A]\nbg = 0000 111111 222222 333333", "line 2: expected four hex colors"),
+            ("[A]\nbg 000000 111111 222222 333333", "line 2: expected key = colors"),
+            ("[A]\nwin = 000000 111111 222222 333333", "line 2: unknown key win"),
+        ];
+
+        for (config, err) in cases {
+            assert_eq!(parse(config).unwrap_err(), err);
+        }
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn presets() {
+        let presets = super::presets();
+        let names: Vec<&str> = presets.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Grey", "DMG green", "Pocket grey", "Light"]);
+
+        // Shades go from lightest to darkest
+        for palette in &presets {
+            let luma: Vec<u32> = palette.bg.iter().map(|c| c.iter().map(|&v| v as u32).sum()).collect();
+            assert!(luma.windows(2).all(|w| w[0] > w[1]), "{}", palette.name);
+            assert_eq!(palette.obj0, palette.bg);
+            assert_eq!(palette.obj1, palette.bg);
+        }
+    }
+}
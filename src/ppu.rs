@@ -1,4 +1,10 @@
+mod fifo;
+
 use crate::bus::Bus;
+use crate::palette::{Palette, Rgb};
+use crate::state::{StateError, StateReader, StateWriter};
+
+use fifo::Fifo;
 
 // Width of screen in pixels.
 const SCREEN_W: u8 = 160;
@@ -9,11 +15,36 @@ const SCREEN_H: u8 = 144;
 enum BGPriority {
     Color0,
     Color123,
+    // Color 1-3 of a CGB tile with the BG-to-OAM priority attribute
+    Priority,
+}
+
+// Sprite selected by OAM scan.
+#[derive(Copy, Clone)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile_no: u8,
+    flags: u8,
+    // OAM index
+    index: u8,
+}
+
+// Scanline rendering method.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Renderer {
+    // Renders a whole line at the start of Pixel Transfer
+    Scanline,
+    // Renders pixel by pixel through the pixel FIFO with accurate timing
+    Fifo,
 }
 
 // Pixel Processing Unit.
 pub struct PPU {
-        vram: [u8; 0x2000],
+        // VRAM bank 0 and 1 (CGB only)
+        vram: [u8; 0x4000],
+        // VRAM Bank
+        vbk: u8,
         oam: [u8; 0xa0],
         // LCD Control
         lcdc: u8,
@@ -39,24 +70,52 @@ pub struct PPU {
         wy: u8,
         // Window X Position minus 7
         wx: u8,
+        // Background Palette Index
+        bcps: u8,
+        // Object Palette Index
+        ocps: u8,
+        // CGB background palette RAM
+        bg_palette: [u8; 0x40],
+        // CGB object palette RAM
+        obj_palette: [u8; 0x40],
+        // Colors of DMG shades
+        palette: Palette,
+        // CGB mode
+        cgb: bool,
+        // Set when H-Blank is entered
+        pub hblank: bool,
         // V-Blank interrupt request
         pub irq_vblank: bool,
         // LCDC interrupt request
         pub irq_lcdc: bool,
+        // Window line counter, advanced only on lines showing the window
+        window_line: u8,
+        // WY matched LY at the start of a line in this frame
+        window_y_triggered: bool,
+        // Window shown on the current line
+        window_drawn: bool,
+        // Window spans the whole line after a line with WX=166
+        window_full_line: bool,
         // Elapsed clocks in current mode
         counter: u16,
-        // Frame buffer
-        frame_buffer: [u8; (SCREEN_W as usize) * (SCREEN_H as usize)],
+        // Length of Pixel Transfer of the current line
+        mode3_len: u16,
+        renderer: Renderer,
+        // Pixel FIFO renderer state
+        fifo: Fifo,
+        // Frame buffer (RGB)
+        frame_buffer: [u8; (SCREEN_W as usize) * (SCREEN_H as usize) * 3],
         // Current scanline
-        scanline: [u8; SCREEN_W as usize],
+        scanline: [Rgb; SCREEN_W as usize],
         // Background priority
         bg_prio: [BGPriority; SCREEN_W as usize],
 }
 
 impl PPU {
-    pub fn new() -> Self {
+    pub fn new(cgb: bool, renderer: Renderer) -> Self {
         PPU {
-            vram: [0; 0x2000],
+            vram: [0; 0x4000],
+            vbk: 0,
             oam: [0; 0xa0],
             lcdc: 0x80,
             stat: 0x02,
@@ -70,50 +129,69 @@ impl PPU {
             obp1: 0,
             wy: 0,
             wx: 0,
+            bcps: 0,
+            ocps: 0,
+            bg_palette: [0xff; 0x40],
+            obj_palette: [0xff; 0x40],
+            palette: Palette::default(),
+            cgb,
+            hblank: false,
             irq_vblank: false,
             irq_lcdc: false,
+            window_line: 0,
+            window_y_triggered: false,
+            window_drawn: false,
+            window_full_line: false,
             counter: 0,
-            scanline: [0; SCREEN_W as usize],
-            frame_buffer: [0; (SCREEN_W as usize) * (SCREEN_H as usize)],
+            mode3_len: 172,
+            renderer,
+            fifo: Fifo::new(),
+            scanline: [[0; 3]; SCREEN_W as usize],
+            frame_buffer: [0; (SCREEN_W as usize) * (SCREEN_H as usize) * 3],
             bg_prio: [BGPriority::Color0; SCREEN_W as usize],
         }
     }
     
     // Fetches tile data from VRAM.
-    fn fetch_tile(&self, tile_no: u8, offset_y: u8, tile_data_sel: bool) -> (u8, u8) {
+    fn fetch_tile(&self, tile_no: u8, offset_y: u8, tile_data_sel: bool, bank: u8) -> (u8, u8) {
         // Fetch tile data from tile set
         let tile_data_addr = if tile_data_sel {
             // Use tile set #1 (0x0000-0x07ff) and #2 (0x0800-0x0fff)
             (tile_no as u16) << 4
         } else {
             // Use tile set #2 (0x0800-0x0fff) and #3 (0x1000-0x17ff)
-            (0x1000 as u16).wrapping_add(((tile_no as i8 as i16) << 4) as u16)
+            0x1000_u16.wrapping_add(((tile_no as i8 as i16) << 4) as u16)
         };
-        let row_addr = tile_data_addr + (offset_y << 1) as u16;
+        let row_addr = (bank as usize) * 0x2000 + (tile_data_addr + (offset_y << 1) as u16) as usize;
 
-        let tile0 = self.vram[row_addr as usize];
-        let tile1 = self.vram[(row_addr + 1) as usize];
+        let tile0 = self.vram[row_addr];
+        let tile1 = self.vram[row_addr + 1];
 
         (tile0, tile1)
     }
     
-    // Fetches BG or Window tile data from VRAM.
+    // Fetches BG or Window tile data and CGB attributes from VRAM.
     fn fetch_bg_window_tile(
         &self,
         tile_x: u8,
         tile_y: u8,
         offset_y: u8,
         tile_map_base: u16,
-    ) -> (u8, u8) {
+    ) -> ((u8, u8), u8) {
         // Fetch tile index from tile map
         let tile_map_addr = tile_map_base | ((tile_x & 0x1f) as u16 + ((tile_y as u16) << 5));
         let tile_no = self.vram[tile_map_addr as usize];
 
-        self.fetch_tile(tile_no, offset_y, self.lcdc & 0x10 > 0)
+        // CGB attributes are stored in VRAM bank 1
+        let attr = if self.cgb { self.vram[0x2000 + tile_map_addr as usize] } else { 0 };
+        let bank = (attr >> 3) & 0x1;
+        let offset_y = if attr & 0x40 > 0 { 7 - offset_y } else { offset_y };
+
+        (self.fetch_tile(tile_no, offset_y, self.lcdc & 0x10 > 0, bank), attr)
     }
     
     // Fetches BG tile data from VRAM.
-    fn fetch_bg_tile(&self, tile_x: u8, tile_y: u8, offset_y: u8) -> (u8, u8) {
+    fn fetch_bg_tile(&self, tile_x: u8, tile_y: u8, offset_y: u8) -> ((u8, u8), u8) {
         // Fetch tile index from tile map
         let tile_map_base = if self.lcdc & 0x8 > 0 { 0x1c00 } else { 0x1800 };
 
@@ -121,21 +199,27 @@ impl PPU {
     }
     
     // Fetches Window tile data from VRAM.
-    fn fetch_window_tile(&self, tile_x: u8, tile_y: u8, offset_y: u8) -> (u8, u8) {
+    fn fetch_window_tile(&self, tile_x: u8, tile_y: u8, offset_y: u8) -> ((u8, u8), u8) {
         // Fetch tile index from tile map
         let tile_map_base = if self.lcdc & 0x40 > 0 { 0x1c00 } else { 0x1800 };
 
         self.fetch_bg_window_tile(tile_x, tile_y, offset_y, tile_map_base)
     }
 
-    // Converts color number to brightness using palette.
-    fn map_color(&self, color_no: u8, palette: u8) -> u8 {
-        match (palette >> (color_no << 1)) & 0x3 {
-            0 => 0xff,
-            1 => 0xaa,
-            2 => 0x55,
-            3 | _ => 0x00,
-        }
+    // Converts color number to RGB using palette.
+    fn map_color(&self, color_no: u8, palette: u8, colors: &[Rgb; 4]) -> Rgb {
+        colors[((palette >> (color_no << 1)) & 0x3) as usize]
+    }
+
+    // Converts color number to RGB using CGB palette RAM.
+    fn map_cgb_color(&self, color_no: u8, palette_no: u8, palette_ram: &[u8; 0x40]) -> Rgb {
+        let ix = ((palette_no as usize) << 3) | ((color_no as usize) << 1);
+        let color = palette_ram[ix] as u16 | (palette_ram[ix + 1] as u16) << 8;
+
+        // Expand 5-bit components to 8 bits
+        let expand = |c: u16| ((c & 0x1f) << 3 | (c & 0x1f) >> 2) as u8;
+
+        [expand(color), expand(color >> 5), expand(color >> 10)]
     }
     
     // Returns the color number at a given position from tile data.
@@ -156,28 +240,35 @@ impl PPU {
         let mut offset_x = self.scx & 0x7;
         let mut offset_y = self.scy.wrapping_add(self.ly) & 0x7;
 
-        let mut tile = self.fetch_bg_tile(tile_x, tile_y, offset_y);
+        let (mut tile, mut attr) = self.fetch_bg_tile(tile_x, tile_y, offset_y);
 
         let mut window = false;
+        let window_x = self.window_x();
 
         for x in 0..SCREEN_W {
-            // Check if window is enabled
-            if self.lcdc & 0x20 > 0 {
-                if self.wy <= self.ly && self.wx == x + 7 {
-                    tile_x = 0;
-                    tile_y = (self.ly - self.wy) >> 3;
-                    offset_x = 0;
-                    offset_y = (self.ly - self.wy) & 0x7;
-                    tile = self.fetch_window_tile(tile_x, tile_y, offset_y);
-                    window = true;
-                }
+            // Switch to window at its left edge
+            if window_x == Some(x) {
+                tile_x = 0;
+                tile_y = self.window_line >> 3;
+                offset_x = self.window_skip();
+                offset_y = self.window_line & 0x7;
+                (tile, attr) = self.fetch_window_tile(tile_x, tile_y, offset_y);
+                window = true;
+                self.window_drawn = true;
             }
 
-            let color_no = self.get_color_no(tile, 7 - offset_x);
-            let color = self.map_color(color_no, self.bgp);
+            let bitpos = if attr & 0x20 > 0 { offset_x } else { 7 - offset_x };
+            let color_no = self.get_color_no(tile, bitpos);
+            let color = if self.cgb {
+                self.map_cgb_color(color_no, attr & 0x7, &self.bg_palette)
+            } else {
+                self.map_color(color_no, self.bgp, &self.palette.bg)
+            };
 
             self.bg_prio[x as usize] = if color_no == 0 {
                 BGPriority::Color0
+            } else if attr & 0x80 > 0 {
+                BGPriority::Priority
             } else {
                 BGPriority::Color123
             };
@@ -192,80 +283,167 @@ impl PPU {
                 tile_x += 1;
 
                 if window {
-                    tile = self.fetch_window_tile(tile_x, tile_y, offset_y);
+                    (tile, attr) = self.fetch_window_tile(tile_x, tile_y, offset_y);
                 } else {
-                    tile = self.fetch_bg_tile(tile_x, tile_y, offset_y);
+                    (tile, attr) = self.fetch_bg_tile(tile_x, tile_y, offset_y);
                 }
             }
         }
     }
     
-    // Renders sprites.
-    fn render_sprites(&mut self) {
-        let mut n_sprites = 0;
+    // Returns the X coordinate where the window starts on the current line.
+    fn window_x(&self) -> Option<u8> {
+        if self.lcdc & 0x20 == 0 || !self.window_y_triggered {
+            return None;
+        }
+
+        // On DMG, clearing LCDC bit 0 also disables the window, so it
+        // neither switches the fetcher nor advances the window line
+        if !self.cgb && self.lcdc & 0x1 == 0 {
+            return None;
+        }
+
+        if self.window_full_line {
+            return Some(0);
+        }
+
+        match self.wx {
+            // WX=0-6 shows the window from the left edge, scrolled left
+            0..=166 => Some(self.wx.saturating_sub(7)),
+            _ => None,
+        }
+    }
+
+    // Returns the number of window pixels cut off at the left edge.
+    fn window_skip(&self) -> u8 {
+        if self.window_full_line {
+            0
+        } else {
+            7u8.saturating_sub(self.wx)
+        }
+    }
+
+    // Latches the window Y condition at the start of OAM Search.
+    fn check_window_y(&mut self) {
+        if self.ly == self.wy {
+            self.window_y_triggered = true;
+        }
+    }
+
+    // Advances the window line counter at the end of Pixel Transfer.
+    fn update_window_line(&mut self) {
+        // The window covers the following line if it was started at WX=166
+        self.window_full_line = self.window_drawn && self.wx == 166;
+
+        if self.window_drawn {
+            self.window_line = self.window_line.wrapping_add(1);
+            self.window_drawn = false;
+        }
+    }
+
+    // Resets the window state at the start of a frame.
+    fn reset_window(&mut self) {
+        self.window_line = 0;
+        self.window_y_triggered = false;
+        self.window_drawn = false;
+        self.window_full_line = false;
+    }
+
+    // Selects the sprites on the current line, up to 10 in OAM order. Sprites
+    // outside the screen horizontally still count towards the limit.
+    fn scan_oam(&self) -> Vec<Sprite> {
         let height = if self.lcdc & 0x4 > 0 { 16 } else { 8 };
+        let mut sprites = Vec::with_capacity(10);
 
         for i in 0..40 {
-            // Parse OAM entry
             let entry_addr = i << 2;
-            let sprite_y = self.oam[entry_addr];
-            let sprite_x = self.oam[entry_addr + 1];
-            let flags = self.oam[entry_addr + 3];
-
-            let obj_prio = flags & 0x80 > 0;
-            let flip_y = flags & 0x40 > 0;
-            let flip_x = flags & 0x20 > 0;
-            let palette = if flags & 0x10 > 0 {
-                self.obp1
-            } else {
-                self.obp0
+            let sprite = Sprite {
+                y: self.oam[entry_addr],
+                x: self.oam[entry_addr + 1],
+                tile_no: self.oam[entry_addr + 2],
+                flags: self.oam[entry_addr + 3],
+                index: i as u8,
             };
 
             // Check if sprite is visible on this scanline
-            if sprite_y <= self.ly + 16 - height || sprite_y > self.ly + 16 {
+            if sprite.y <= self.ly + 16 - height || sprite.y > self.ly + 16 {
                 continue;
             }
 
             // Up to 10 sprites can be rendered on one scanline
-            n_sprites += 1;
-            if n_sprites > 10 {
+            sprites.push(sprite);
+            if sprites.len() >= 10 {
                 break;
             }
+        }
+
+        sprites
+    }
 
+    // Fetches the tile data of a sprite on the current line.
+    fn fetch_sprite_tile(&self, sprite: &Sprite) -> (u8, u8) {
+        let flip_y = sprite.flags & 0x40 > 0;
+
+        // Tile number
+        let tile_no = if self.lcdc & 0x4 > 0 {
+            // 8x16 sprite
+            if (self.ly + 8 < sprite.y) ^ flip_y {
+                sprite.tile_no & 0xfe
+            } else {
+                sprite.tile_no | 0x01
+            }
+        } else {
+            // 8x8 sprite
+            sprite.tile_no
+        };
+
+        // Y-offset within the tile
+        let offset_y = if flip_y {
+            7 - ((self.ly + 16 - sprite.y) & 0x7)
+        } else {
+            (self.ly + 16 - sprite.y) & 0x7
+        };
+
+        let bank = if self.cgb { (sprite.flags >> 3) & 0x1 } else { 0 };
+        self.fetch_tile(tile_no, offset_y, true, bank)
+    }
+
+    // Renders sprites.
+    fn render_sprites(&mut self) {
+        let mut sprites = self.scan_oam();
+
+        // On DMG, the sprite with the lowest X coordinate is drawn on top, the
+        // lowest OAM index breaking ties. On CGB, only OAM index matters.
+        if !self.cgb {
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+
+        // Pixels already taken by a sprite of higher priority
+        let mut drawn = [false; SCREEN_W as usize];
+
+        for sprite in sprites {
             // Check if sprite is within the screen
-            if sprite_x == 0 || sprite_x > SCREEN_W + 8 - 1 {
+            if sprite.x == 0 || sprite.x >= SCREEN_W + 8 {
                 continue;
             }
 
-            // Tile number
-            let tile_no = if self.lcdc & 0x4 > 0 {
-                // 8x16 sprite
-                if (self.ly + 8 < sprite_y) ^ flip_y {
-                    self.oam[entry_addr + 2] & 0xfe
-                } else {
-                    self.oam[entry_addr + 2] | 0x01
-                }
-            } else {
-                // 8x8 sprite
-                self.oam[entry_addr + 2]
-            };
-
-            // Y-offset within the tile
-            let offset_y = if flip_y {
-                7 - ((self.ly + 16 - sprite_y) & 0x7)
+            let obj_prio = sprite.flags & 0x80 > 0;
+            let flip_x = sprite.flags & 0x20 > 0;
+            let (palette, colors) = if sprite.flags & 0x10 > 0 {
+                (self.obp1, &self.palette.obj1)
             } else {
-                (self.ly + 16 - sprite_y) & 0x7
+                (self.obp0, &self.palette.obj0)
             };
 
             // Fetch tile data
-            let tile = self.fetch_tile(tile_no, offset_y, true);
+            let tile = self.fetch_sprite_tile(&sprite);
 
             for offset_x in 0..8 {
-                if offset_x + sprite_x < 8 {
+                if offset_x + sprite.x < 8 {
                     continue;
                 }
 
-                let x = offset_x + sprite_x - 8;
+                let x = offset_x + sprite.x - 8;
 
                 if x >= SCREEN_W {
                     break;
@@ -273,13 +451,24 @@ impl PPU {
 
                 let bitpos = if flip_x { offset_x } else { 7 - offset_x };
                 let color_no = self.get_color_no(tile, bitpos);
-                if color_no == 0 {
+                if color_no == 0 || drawn[x as usize] {
                     continue;
                 }
-                if self.bg_prio[x as usize] == BGPriority::Color123 && obj_prio {
-                    continue;
+                // A sprite hidden behind BG still hides sprites of lower priority
+                drawn[x as usize] = true;
+
+                // On CGB, clearing LCDC bit 0 puts sprites above BG and window
+                let bg_master_prio = !self.cgb || self.lcdc & 0x1 > 0;
+                match self.bg_prio[x as usize] {
+                    BGPriority::Priority if bg_master_prio => continue,
+                    BGPriority::Color123 if bg_master_prio && obj_prio => continue,
+                    _ => (),
                 }
-                let color = self.map_color(color_no, palette);
+                let color = if self.cgb {
+                    self.map_cgb_color(color_no, sprite.flags & 0x7, &self.obj_palette)
+                } else {
+                    self.map_color(color_no, palette, colors)
+                };
 
                 self.scanline[x as usize] = color;
             }
@@ -288,24 +477,127 @@ impl PPU {
 
     // Renders a scanline.
     fn render_scanline(&mut self) {
-        if self.lcdc & 0x1 > 0 {
+        // On CGB, LCDC bit 0 is the BG and window master priority instead
+        if self.cgb || self.lcdc & 0x1 > 0 {
             self.render_bg();
+        } else {
+            self.scanline = [self.palette.bg[0]; SCREEN_W as usize];
+            self.bg_prio = [BGPriority::Color0; SCREEN_W as usize];
         }
         if self.lcdc & 0x2 > 0 {
             self.render_sprites();
         }
 
         for x in 0..SCREEN_W {
-            let ix = (x as usize) + (self.ly as usize) * (SCREEN_W as usize);
-            self.frame_buffer[ix] = self.scanline[x as usize];
+            let ix = ((x as usize) + (self.ly as usize) * (SCREEN_W as usize)) * 3;
+            self.frame_buffer[ix..ix + 3].copy_from_slice(&self.scanline[x as usize]);
         }
     }
     
-    // Returns the current contents of the frame buffer.
+    // Writes a byte transferred by OAM DMA, regardless of the LCD mode.
+    pub fn write_oam_dma(&mut self, index: u8, val: u8) {
+        self.oam[index as usize] = val;
+    }
+
+    // Sets the colors used for DMG shades.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    // Returns the current contents of the frame buffer (RGB).
     pub fn frame_buffer(&self) -> &[u8] {
         &self.frame_buffer
     }
 
+    // Serializes memory, registers and timing. The DMG colors and renderer
+    // are settings and not part of the state.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram);
+        w.u8(self.vbk);
+        w.bytes(&self.oam);
+        for reg in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.dma, self.bgp,
+            self.obp0, self.obp1, self.wy, self.wx, self.bcps, self.ocps,
+        ] {
+            w.u8(reg);
+        }
+        w.bytes(&self.bg_palette);
+        w.bytes(&self.obj_palette);
+        w.bool(self.cgb);
+        w.bool(self.hblank);
+        w.bool(self.irq_vblank);
+        w.bool(self.irq_lcdc);
+        w.u8(self.window_line);
+        w.bool(self.window_y_triggered);
+        w.bool(self.window_drawn);
+        w.bool(self.window_full_line);
+        w.u16(self.counter);
+        w.u16(self.mode3_len);
+        w.bytes(&self.frame_buffer);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.vram)?;
+        self.vbk = r.u8()?;
+        r.bytes(&mut self.oam)?;
+        for reg in [
+            &mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly,
+            &mut self.lyc, &mut self.dma, &mut self.bgp, &mut self.obp0, &mut self.obp1,
+            &mut self.wy, &mut self.wx, &mut self.bcps, &mut self.ocps,
+        ] {
+            *reg = r.u8()?;
+        }
+        r.bytes(&mut self.bg_palette)?;
+        r.bytes(&mut self.obj_palette)?;
+        self.cgb = r.bool()?;
+        self.hblank = r.bool()?;
+        self.irq_vblank = r.bool()?;
+        self.irq_lcdc = r.bool()?;
+        self.window_line = r.u8()?;
+        self.window_y_triggered = r.bool()?;
+        self.window_drawn = r.bool()?;
+        self.window_full_line = r.bool()?;
+        self.counter = r.u16()?;
+        self.mode3_len = r.u16()?;
+        r.bytes(&mut self.frame_buffer)?;
+
+        // Pixel Transfer and H-Blank share 376 clocks of a line
+        if self.vbk > 1 || self.ly > 153 || self.mode3_len > 376 {
+            return Err(StateError::Invalid("LCD state out of range"));
+        }
+
+        // Pixel FIFO state is not saved, so a line in Pixel Transfer is
+        // rendered again up to the current dot
+        if self.renderer == Renderer::Fifo && self.stat & 0x3 == 3 {
+            self.start_fifo();
+        }
+        Ok(())
+    }
+
+    // Returns the index into VRAM of the currently selected bank.
+    fn vram_index(&self, addr: u16) -> usize {
+        (self.vbk as usize) * 0x2000 + (addr & 0x1fff) as usize
+    }
+
+    // Advances a palette index register if auto increment is enabled.
+    fn increment_palette_index(index: u8) -> u8 {
+        if index & 0x80 > 0 {
+            0x80 | ((index + 1) & 0x3f)
+        } else {
+            index
+        }
+    }
+
+    // Sets the state left by the boot ROM, which hands over during line 153
+    // of V-Blank with LY already reading 0.
+    pub(crate) fn init_post_boot(&mut self) {
+        self.ly = 0;
+        self.counter = 4;
+        self.stat = (self.stat & 0xf8) | 1;
+        self.update_lyc_interrupt();
+        self.irq_lcdc = false;
+    }
+
     // Checks LYC interrupt.
     fn update_lyc_interrupt(&mut self) {
         // LYC=LY coincidence interrupt
@@ -342,7 +634,7 @@ impl Bus for PPU {
             0x8000..=0x9fff => {
                 // VRAM is inaccessible during pixel transfer
                 if self.stat & 0x3 != 3 {
-                    self.vram[(addr & 0x1fff) as usize] = val
+                    self.vram[self.vram_index(addr)] = val
                 }
             }
 
@@ -363,6 +655,9 @@ impl Bus for PPU {
                     let mode = if val & 0x80 > 0 { 2 } else { 0 };
                     self.stat = (self.stat & 0xf8) | mode;
                     self.update_mode_interrupt();
+
+                    self.reset_window();
+                    self.check_window_y();
                 }
 
                 self.lcdc = val;
@@ -377,11 +672,23 @@ impl Bus for PPU {
                     self.update_lyc_interrupt();
                 }
             }
+            0xff46 => self.dma = val,
             0xff47 => self.bgp = val,
             0xff48 => self.obp0 = val,
             0xff49 => self.obp1 = val,
             0xff4a => self.wy = val,
             0xff4b => self.wx = val,
+            0xff4f => self.vbk = val & 0x1,
+            0xff68 => self.bcps = val & 0xbf,
+            0xff69 => {
+                self.bg_palette[(self.bcps & 0x3f) as usize] = val;
+                self.bcps = Self::increment_palette_index(self.bcps);
+            }
+            0xff6a => self.ocps = val & 0xbf,
+            0xff6b => {
+                self.obj_palette[(self.ocps & 0x3f) as usize] = val;
+                self.ocps = Self::increment_palette_index(self.ocps);
+            }
 
             _ => unreachable!("Unexpected address: 0x{:04x}", addr),
         }
@@ -393,7 +700,7 @@ impl Bus for PPU {
             0x8000..=0x9fff => {
                 // VRAM is inaccessible during pixel transfer
                 if self.stat & 0x3 != 3 {
-                    self.vram[(addr & 0x1fff) as usize]
+                    self.vram[self.vram_index(addr)]
                 } else {
                     0xff
                 }
@@ -411,7 +718,8 @@ impl Bus for PPU {
 
             // IO registers
             0xff40 => self.lcdc,
-            0xff41 => self.stat,
+            // Bit 7 is unused and always reads 1
+            0xff41 => 0x80 | self.stat,
             0xff42 => self.scy,
             0xff43 => self.scx,
             0xff44 => self.ly,
@@ -422,6 +730,11 @@ impl Bus for PPU {
             0xff49 => self.obp1,
             0xff4a => self.wy,
             0xff4b => self.wx,
+            0xff4f => 0xfe | self.vbk,
+            0xff68 => 0x40 | self.bcps,
+            0xff69 => self.bg_palette[(self.bcps & 0x3f) as usize],
+            0xff6a => 0x40 | self.ocps,
+            0xff6b => self.obj_palette[(self.ocps & 0x3f) as usize],
 
             _ => unreachable!("Unexpected address: 0x{:04x}", addr),
         }
@@ -441,22 +754,34 @@ impl Bus for PPU {
                     self.counter -= 80;
                     // Transition to Pixel Transfer mode
                     self.stat = (self.stat & 0xf8) | 3;
-                    self.render_scanline();
+                    match self.renderer {
+                        Renderer::Scanline => self.render_scanline(),
+                        Renderer::Fifo => self.start_fifo(),
+                    }
                 }
             }
-            // Pixel Transfer (172 clocks)
+            // Pixel Transfer (172-289 clocks)
             3 => {
-                if self.counter >= 172 {
-                    self.counter -= 172;
+                let done = match self.renderer {
+                    Renderer::Scanline => self.counter >= self.mode3_len,
+                    Renderer::Fifo => self.update_fifo(),
+                };
+                if done {
+                    self.counter -= self.mode3_len;
+                    self.update_window_line();
+
                     // Transition to H-Blank mode
-                    self.stat = self.stat & 0xf8;
+                    self.stat &= 0xf8;
+                    self.hblank = true;
                     self.update_mode_interrupt();
                 }
             }
-            // H-Blank (204 clocks)
+            // H-Blank (87-204 clocks)
             0 => {
-                if self.counter >= 204 {
-                    self.counter -= 204;
+                // Pixel Transfer and H-Blank take 376 clocks together
+                let hblank_len = 376_u16.saturating_sub(self.mode3_len);
+                if self.counter >= hblank_len {
+                    self.counter -= hblank_len;
                     self.ly += 1;
 
                     if self.ly >= SCREEN_H {
@@ -466,6 +791,7 @@ impl Bus for PPU {
                     } else {
                         // Transition to OAM Search mode
                         self.stat = (self.stat & 0xf8) | 2;
+                        self.check_window_y();
                     }
 
                     self.update_lyc_interrupt();
@@ -473,23 +799,278 @@ impl Bus for PPU {
                 }
             }
             // V-Blank (4560 clocks or 10 lines)
-            1 | _ => {
+            _ => {
+                // LY reads 0 for all but the first 4 clocks of line 153
+                if self.ly == 153 && self.counter >= 4 {
+                    self.ly = 0;
+                    self.update_lyc_interrupt();
+                }
+
                 if self.counter >= 456 {
                     self.counter -= 456;
-                    self.ly += 1;
 
-                    if self.ly >= 154 {
+                    if self.ly == 0 {
                         // Transition to OAM Search mode
                         self.stat = (self.stat & 0xf8) | 2;
-                        self.ly = 0;
+
+                        self.reset_window();
+                        self.check_window_y();
 
                         self.update_mode_interrupt();
+                    } else {
+                        self.ly += 1;
+                        self.update_lyc_interrupt();
                     }
-
-                    self.update_lyc_interrupt();
                 }
             }
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::reload;
+
+    // Returns a DMG PPU at the start of line 0 with BG and window enabled.
+    // The BG shows color 1; window tiles show color 3 on their left half
+    // and color 0 on their right half.
+    fn window_ppu(renderer: Renderer, wx: u8, wy: u8) -> PPU {
+        let mut ppu = PPU::new(false, renderer);
+        ppu.lcdc = 0xf1;
+        ppu.bgp = 0xe4;
+        ppu.wx = wx;
+        ppu.wy = wy;
+
+        // Tile 1 for the window, tile 2 for the BG
+        for row in 0..8 {
+            ppu.vram[0x10 + row * 2] = 0xf0;
+            ppu.vram[0x11 + row * 2] = 0xf0;
+            ppu.vram[0x20 + row * 2] = 0xff;
+        }
+        ppu.vram[0x1800..0x1c00].fill(2);
+        ppu.vram[0x1c00..0x2000].fill(1);
+
+        ppu.check_window_y();
+        ppu
+    }
+
+    // Runs the PPU for one whole line.
+    fn step_line(ppu: &mut PPU) {
+        for _ in 0..114 {
+            ppu.update(4);
+        }
+    }
+
+    // Returns a PPU at the start of line 0 with a blank BG and sprite tiles
+    // 1-3 filled with color numbers 1-3. On CGB, palette 0 holds 4 distinct
+    // colors for both BG and sprites.
+    fn sprite_ppu(cgb: bool, renderer: Renderer) -> PPU {
+        let mut ppu = PPU::new(cgb, renderer);
+        ppu.lcdc = 0x93;
+        ppu.bgp = 0xe4;
+        ppu.obp0 = 0xe4;
+
+        for row in 0..8 {
+            ppu.vram[0x10 + row * 2] = 0xff;
+            ppu.vram[0x21 + row * 2] = 0xff;
+            ppu.vram[0x30 + row * 2] = 0xff;
+            ppu.vram[0x31 + row * 2] = 0xff;
+        }
+        let colors = [0x00, 0x00, 0x1f, 0x00, 0xe0, 0x03, 0x00, 0x7c];
+        ppu.bg_palette[..8].copy_from_slice(&colors);
+        ppu.obj_palette[..8].copy_from_slice(&colors);
+        ppu
+    }
+
+    // Places a sprite on line 0.
+    fn set_sprite(ppu: &mut PPU, index: usize, x: u8, tile_no: u8) {
+        ppu.oam[index << 2..(index << 2) + 4].copy_from_slice(&[16, x, tile_no, 0]);
+    }
+
+    // Returns the color numbers of the given line in the frame buffer.
+    fn line_colors(ppu: &PPU, ly: usize) -> Vec<usize> {
+        let colors: Vec<Rgb> = if ppu.cgb {
+            (0..4).map(|n| ppu.map_cgb_color(n, 0, &ppu.bg_palette)).collect()
+        } else {
+            ppu.palette.bg.to_vec()
+        };
+        let row = &ppu.frame_buffer[ly * SCREEN_W as usize * 3..(ly + 1) * SCREEN_W as usize * 3];
+        row.chunks(3)
+            .map(|rgb| colors.iter().position(|c| c[..] == *rgb).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn dmg_sprite_priority() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            // The sprite with the lower X is drawn on top
+            let mut ppu = sprite_ppu(false, renderer);
+            set_sprite(&mut ppu, 0, 20, 1);
+            set_sprite(&mut ppu, 1, 16, 2);
+            step_line(&mut ppu);
+            assert_eq!(line_colors(&ppu, 0)[8..20], [2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1]);
+
+            // With the same X, the lower OAM index is drawn on top
+            let mut ppu = sprite_ppu(false, renderer);
+            set_sprite(&mut ppu, 0, 16, 1);
+            set_sprite(&mut ppu, 1, 16, 2);
+            step_line(&mut ppu);
+            assert_eq!(line_colors(&ppu, 0)[8..16], [1; 8]);
+        }
+    }
+
+    #[test]
+    fn cgb_sprite_priority() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            // Only the OAM index matters
+            let mut ppu = sprite_ppu(true, renderer);
+            set_sprite(&mut ppu, 0, 20, 1);
+            set_sprite(&mut ppu, 1, 16, 2);
+            step_line(&mut ppu);
+            assert_eq!(line_colors(&ppu, 0)[8..20], [2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1]);
+
+            // A transparent pixel of the higher priority sprite shows the other
+            let mut ppu = sprite_ppu(true, renderer);
+            for row in 0..8 {
+                ppu.vram[0x10 + row * 2] = 0x0f;
+            }
+            set_sprite(&mut ppu, 0, 16, 1);
+            set_sprite(&mut ppu, 1, 16, 3);
+            step_line(&mut ppu);
+            assert_eq!(line_colors(&ppu, 0)[8..16], [3, 3, 3, 3, 1, 1, 1, 1]);
+        }
+    }
+
+    #[test]
+    fn ten_sprites_per_line() {
+        for cgb in [false, true] {
+            for renderer in [Renderer::Scanline, Renderer::Fifo] {
+                let mut ppu = sprite_ppu(cgb, renderer);
+                // A sprite off screen still takes up a slot
+                set_sprite(&mut ppu, 0, 0, 3);
+                for i in 1..11 {
+                    set_sprite(&mut ppu, i, 8 * i as u8 + 8, 3);
+                }
+                step_line(&mut ppu);
+
+                let colors = line_colors(&ppu, 0);
+                assert!(colors[8..80].iter().all(|&c| c == 3));
+                assert!(colors[80..].iter().all(|&c| c == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn window_line_counts_drawn_lines() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = window_ppu(renderer, 7, 0);
+            for _ in 0..3 {
+                step_line(&mut ppu);
+            }
+            assert_eq!(ppu.window_line, 3);
+
+            // Lines where the window is off screen don't count
+            ppu.wx = 200;
+            step_line(&mut ppu);
+            ppu.lcdc &= !0x20;
+            step_line(&mut ppu);
+            assert_eq!(ppu.window_line, 3);
+
+            ppu.wx = 7;
+            ppu.lcdc |= 0x20;
+            step_line(&mut ppu);
+            assert_eq!(ppu.window_line, 4);
+        }
+    }
+
+    #[test]
+    fn dmg_lcdc_bit_0_disables_window() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = window_ppu(renderer, 7, 0);
+            ppu.lcdc &= !0x1;
+            for _ in 0..3 {
+                step_line(&mut ppu);
+            }
+            assert_eq!(ppu.window_line, 0);
+
+            // On CGB, the bit is the master priority instead
+            ppu.cgb = true;
+            step_line(&mut ppu);
+            assert_eq!(ppu.window_line, 1);
+        }
+    }
+
+    #[test]
+    fn window_left_of_wx_7_is_cut_off() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = window_ppu(renderer, 3, 0);
+            step_line(&mut ppu);
+            let colors = line_colors(&ppu, 0);
+            // The first 4 window pixels are hidden
+            assert_eq!(colors[..12], [0, 0, 0, 0, 3, 3, 3, 3, 0, 0, 0, 0]);
+
+            let mut ppu = window_ppu(renderer, 10, 0);
+            step_line(&mut ppu);
+            let colors = line_colors(&ppu, 0);
+            assert_eq!(colors[..11], [1, 1, 1, 3, 3, 3, 3, 0, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn window_y_is_checked_per_line() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            let mut ppu = window_ppu(renderer, 7, 100);
+            for _ in 0..20 {
+                step_line(&mut ppu);
+            }
+            // LY has already passed the new WY on this frame
+            ppu.wy = 10;
+            while ppu.ly != 0 || ppu.stat & 0x3 != 2 {
+                ppu.update(4);
+            }
+            assert_eq!(ppu.window_line, 0);
+            assert!(line_colors(&ppu, 143).iter().all(|&c| c == 1));
+
+            // On the next frame the window starts at line 10, and stays on
+            // after WY moves below the current line
+            for _ in 0..12 {
+                step_line(&mut ppu);
+            }
+            ppu.wy = 0;
+            for _ in 0..3 {
+                step_line(&mut ppu);
+            }
+            assert!(line_colors(&ppu, 9).iter().all(|&c| c == 1));
+            assert_eq!(line_colors(&ppu, 10)[..8], [3, 3, 3, 3, 0, 0, 0, 0]);
+            assert_eq!(ppu.window_line, 5);
+        }
+    }
+
+    #[test]
+    fn out_of_range_state_is_rejected() {
+        let mut ppu = PPU::new(false, Renderer::Scanline);
+        let mut loaded = PPU::new(false, Renderer::Scanline);
+        ppu.mode3_len = 376;
+        reload(|w| ppu.save_state(w), |r| loaded.load_state(r)).unwrap();
+
+        ppu.mode3_len = 377;
+        let result = reload(|w| ppu.save_state(w), |r| loaded.load_state(r));
+        assert!(matches!(result, Err(StateError::Invalid(_))));
+    }
+
+    #[test]
+    fn long_pixel_transfer_leaves_no_hblank() {
+        let mut ppu = PPU::new(false, Renderer::Scanline);
+        ppu.lcdc = 0x80;
+        ppu.stat = 0x00;
+        ppu.ly = 0;
+        ppu.counter = 0;
+        ppu.mode3_len = 400;
+
+        ppu.update(4);
+        assert_eq!(ppu.ly, 1);
+        assert_eq!(ppu.stat & 0x3, 2);
+    }
+}
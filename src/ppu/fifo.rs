@@ -0,0 +1,396 @@
+use std::collections::VecDeque;
+
+use super::{BGPriority, Sprite, PPU, SCREEN_W};
+
+// Dots of the discarded first tile fetch at the start of a line.
+const INITIAL_FETCH_DELAY: u8 = 6;
+// Dots taken by a sprite fetch.
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+// Pixel in the BG FIFO.
+#[derive(Copy, Clone)]
+struct BgPixel {
+    color_no: u8,
+    // CGB palette number
+    palette: u8,
+    prio: BGPriority,
+}
+
+// Pixel in the sprite FIFO.
+#[derive(Copy, Clone)]
+struct ObjPixel {
+    color_no: u8,
+    // OAM attribute flags
+    flags: u8,
+    // OAM index
+    index: u8,
+}
+
+impl ObjPixel {
+    const TRANSPARENT: ObjPixel = ObjPixel {
+        color_no: 0,
+        flags: 0,
+        index: 0xff,
+    };
+}
+
+// State of the pixel FIFO renderer during Pixel Transfer.
+pub struct Fifo {
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<ObjPixel>,
+    // Dot within the current BG/Window tile fetch
+    step: u8,
+    // Tile column fetched next
+    fetch_x: u8,
+    // Tile number, attributes and data latched by the fetcher
+    tile_no: u8,
+    attr: u8,
+    tile: (u8, u8),
+    // Fetching Window tiles
+    window: bool,
+    // Dots to wait before the first fetch
+    delay: u8,
+    // Pixels left to discard for fine scrolling
+    discard: u8,
+    // X coordinate of the next pixel
+    lx: u8,
+    // Sprites on this line not fetched yet, in order of X coordinate
+    sprites: Vec<Sprite>,
+    // Dots left in the current sprite fetch
+    sprite_dots: u8,
+    // Dots elapsed in Pixel Transfer
+    dots: u16,
+    // All pixels of the line are pushed out
+    done: bool,
+}
+
+impl Fifo {
+    pub fn new() -> Self {
+        Fifo {
+            bg: VecDeque::with_capacity(8),
+            obj: VecDeque::with_capacity(8),
+            step: 0,
+            fetch_x: 0,
+            tile_no: 0,
+            attr: 0,
+            tile: (0, 0),
+            window: false,
+            delay: 0,
+            discard: 0,
+            lx: 0,
+            sprites: Vec::with_capacity(10),
+            sprite_dots: 0,
+            dots: 0,
+            done: false,
+        }
+    }
+}
+
+impl PPU {
+    // Starts Pixel Transfer of the current line.
+    pub(super) fn start_fifo(&mut self) {
+        let mut sprites = self.scan_oam();
+        // Sprites are fetched as the line reaches them
+        sprites.sort_by_key(|sprite| sprite.x);
+        sprites.reverse();
+
+        self.fifo = Fifo {
+            delay: INITIAL_FETCH_DELAY,
+            discard: self.scx & 0x7,
+            sprites,
+            ..Fifo::new()
+        };
+    }
+
+    // Runs Pixel Transfer up to the elapsed clocks. Returns true when the
+    // line is complete, with its length stored in `mode3_len`.
+    pub(super) fn update_fifo(&mut self) -> bool {
+        while !self.fifo.done && self.fifo.dots < self.counter {
+            self.step_fifo();
+        }
+
+        if self.fifo.done {
+            self.mode3_len = self.fifo.dots;
+        }
+        self.fifo.done
+    }
+
+    // Advances the fetchers and FIFOs by one dot.
+    fn step_fifo(&mut self) {
+        self.fifo.dots += 1;
+
+        // BG fetching and pixel output are suspended during a sprite fetch
+        if self.fifo.sprite_dots > 0 {
+            self.fifo.sprite_dots -= 1;
+            if self.fifo.sprite_dots == 0 {
+                self.fetch_sprite();
+            }
+            return;
+        }
+
+        if !self.fifo.window && self.window_x() == Some(self.fifo.lx) {
+            // Restart fetching from the first Window tile
+            self.fifo.window = true;
+            self.fifo.bg.clear();
+            self.fifo.step = 0;
+            self.fifo.fetch_x = 0;
+            self.fifo.discard = self.window_skip();
+            self.window_drawn = true;
+        }
+
+        if self.sprite_reached() {
+            // A sprite fetch waits until the BG fetcher has finished the
+            // current tile and the BG FIFO holds pixels. Pixel output stays
+            // suspended meanwhile, so both delays lengthen Pixel Transfer.
+            if self.fifo.step > 5 && !self.fifo.bg.is_empty() {
+                // This dot is the first of the sprite fetch
+                self.fifo.sprite_dots = SPRITE_FETCH_DOTS - 1;
+                return;
+            }
+            self.step_fetcher();
+            return;
+        }
+
+        self.step_fetcher();
+        self.shift_pixel();
+    }
+
+    // Returns true if a sprite starts at the next pixel.
+    fn sprite_reached(&self) -> bool {
+        if self.lcdc & 0x2 == 0 || self.fifo.discard > 0 {
+            return false;
+        }
+
+        match self.fifo.sprites.last() {
+            Some(sprite) => sprite.x <= self.fifo.lx + 8,
+            None => false,
+        }
+    }
+
+    // Advances the BG/Window tile fetcher by one dot.
+    fn step_fetcher(&mut self) {
+        if self.fifo.delay > 0 {
+            self.fifo.delay -= 1;
+            return;
+        }
+
+        match self.fifo.step {
+            0..=5 => {
+                match self.fifo.step {
+                    1 => self.fetch_tile_no(),
+                    3 => self.fifo.tile.0 = self.fetch_fifo_tile().0,
+                    5 => self.fifo.tile.1 = self.fetch_fifo_tile().1,
+                    _ => (),
+                }
+                self.fifo.step += 1;
+            }
+            // Push the tile once the FIFO is empty, reading the next tile
+            // number in the same dot
+            _ => {
+                if self.fifo.bg.is_empty() {
+                    self.push_tile();
+                    self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+                    self.fetch_tile_no();
+                    self.fifo.step = 2;
+                }
+            }
+        }
+    }
+
+    // Returns the tile row and Y-offset within the tile being fetched.
+    fn fetch_row(&self) -> (u8, u8) {
+        let y = if self.fifo.window {
+            self.window_line
+        } else {
+            self.ly.wrapping_add(self.scy)
+        };
+
+        (y >> 3, y & 0x7)
+    }
+
+    // Reads the tile number and CGB attributes from the tile map.
+    fn fetch_tile_no(&mut self) {
+        let (tile_map_base, tile_x) = if self.fifo.window {
+            let base = if self.lcdc & 0x40 > 0 { 0x1c00 } else { 0x1800 };
+            (base, self.fifo.fetch_x)
+        } else {
+            let base = if self.lcdc & 0x8 > 0 { 0x1c00 } else { 0x1800 };
+            (base, (self.scx >> 3).wrapping_add(self.fifo.fetch_x))
+        };
+        let (tile_y, _) = self.fetch_row();
+
+        let tile_map_addr = tile_map_base | ((tile_x & 0x1f) as u16 + ((tile_y as u16) << 5));
+        self.fifo.tile_no = self.vram[tile_map_addr as usize];
+        self.fifo.attr = if self.cgb { self.vram[0x2000 + tile_map_addr as usize] } else { 0 };
+    }
+
+    // Reads the tile data of the latched tile number.
+    fn fetch_fifo_tile(&self) -> (u8, u8) {
+        let (_, offset_y) = self.fetch_row();
+        let attr = self.fifo.attr;
+        let offset_y = if attr & 0x40 > 0 { 7 - offset_y } else { offset_y };
+
+        self.fetch_tile(self.fifo.tile_no, offset_y, self.lcdc & 0x10 > 0, (attr >> 3) & 0x1)
+    }
+
+    // Pushes the fetched tile into the BG FIFO.
+    fn push_tile(&mut self) {
+        let attr = self.fifo.attr;
+
+        for offset_x in 0..8 {
+            let bitpos = if attr & 0x20 > 0 { offset_x } else { 7 - offset_x };
+            let color_no = self.get_color_no(self.fifo.tile, bitpos);
+            let prio = if color_no == 0 {
+                BGPriority::Color0
+            } else if attr & 0x80 > 0 {
+                BGPriority::Priority
+            } else {
+                BGPriority::Color123
+            };
+
+            self.fifo.bg.push_back(BgPixel {
+                color_no,
+                palette: attr & 0x7,
+                prio,
+            });
+        }
+    }
+
+    // Fetches the next sprite and merges it into the sprite FIFO.
+    fn fetch_sprite(&mut self) {
+        let sprite = match self.fifo.sprites.pop() {
+            Some(sprite) => sprite,
+            None => return,
+        };
+        let tile = self.fetch_sprite_tile(&sprite);
+        let flip_x = sprite.flags & 0x20 > 0;
+
+        // Pixels left of the current position are not drawn
+        let skip = (self.fifo.lx + 8).saturating_sub(sprite.x).min(8);
+
+        while self.fifo.obj.len() < (8 - skip) as usize {
+            self.fifo.obj.push_back(ObjPixel::TRANSPARENT);
+        }
+
+        for offset_x in skip..8 {
+            let bitpos = if flip_x { offset_x } else { 7 - offset_x };
+            let pixel = ObjPixel {
+                color_no: self.get_color_no(tile, bitpos),
+                flags: sprite.flags,
+                index: sprite.index,
+            };
+
+            // DMG keeps the sprite fetched first, CGB the lowest OAM index
+            let slot = &mut self.fifo.obj[(offset_x - skip) as usize];
+            if slot.color_no == 0 || (self.cgb && pixel.color_no != 0 && pixel.index < slot.index) {
+                *slot = pixel;
+            }
+        }
+    }
+
+    // Shifts one pixel out of the FIFOs to the frame buffer.
+    fn shift_pixel(&mut self) {
+        let bg = match self.fifo.bg.pop_front() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let obj = self.fifo.obj.pop_front().unwrap_or(ObjPixel::TRANSPARENT);
+
+        // On DMG, clearing LCDC bit 0 blanks BG and window
+        let bg_enabled = self.cgb || self.lcdc & 0x1 > 0;
+        let bg_prio = if bg_enabled { bg.prio } else { BGPriority::Color0 };
+        let bg_color = if self.cgb {
+            self.map_cgb_color(bg.color_no, bg.palette, &self.bg_palette)
+        } else if bg_enabled {
+            self.map_color(bg.color_no, self.bgp, &self.palette.bg)
+        } else {
+            self.palette.bg[0]
+        };
+
+        // On CGB, clearing LCDC bit 0 puts sprites above BG and window
+        let bg_master_prio = !self.cgb || self.lcdc & 0x1 > 0;
+        let obj_visible = obj.color_no != 0
+            && self.lcdc & 0x2 > 0
+            && match bg_prio {
+                BGPriority::Priority => !bg_master_prio,
+                BGPriority::Color123 => !bg_master_prio || obj.flags & 0x80 == 0,
+                BGPriority::Color0 => true,
+            };
+
+        let color = if !obj_visible {
+            bg_color
+        } else if self.cgb {
+            self.map_cgb_color(obj.color_no, obj.flags & 0x7, &self.obj_palette)
+        } else if obj.flags & 0x10 > 0 {
+            self.map_color(obj.color_no, self.obp1, &self.palette.obj1)
+        } else {
+            self.map_color(obj.color_no, self.obp0, &self.palette.obj0)
+        };
+
+        let ix = ((self.fifo.lx as usize) + (self.ly as usize) * (SCREEN_W as usize)) * 3;
+        self.frame_buffer[ix..ix + 3].copy_from_slice(&color);
+
+        self.fifo.lx += 1;
+        if self.fifo.lx >= SCREEN_W {
+            self.fifo.done = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::Renderer;
+
+    // Returns the Pixel Transfer length of line 0 with sprites at the given
+    // OAM X coordinates.
+    fn mode3_len(scx: u8, sprite_xs: &[u8]) -> u16 {
+        let mut ppu = PPU::new(false, Renderer::Fifo);
+        ppu.lcdc = 0x83;
+        ppu.scx = scx;
+        for (i, &x) in sprite_xs.iter().enumerate() {
+            ppu.oam[i << 2] = 16;
+            ppu.oam[(i << 2) + 1] = x;
+        }
+
+        ppu.start_fifo();
+        ppu.counter = u16::MAX;
+        assert!(ppu.update_fifo());
+        ppu.mode3_len
+    }
+
+    #[test]
+    fn line_without_sprites() {
+        assert_eq!(mode3_len(0, &[]), 172);
+        // Scrolled out pixels of the first tile are discarded
+        assert_eq!(mode3_len(3, &[]), 175);
+    }
+
+    #[test]
+    fn sprite_fetch_waits_for_bg_fetch() {
+        for scx in [0, 3] {
+            let base = mode3_len(scx, &[]);
+            for x in 8..=40 {
+                // The BG fetch of the tile under the sprite has to finish
+                // first, then the sprite fetch takes 6 dots
+                let penalty = 6 + 5u16.saturating_sub(((x + scx) & 0x7) as u16);
+                assert_eq!(mode3_len(scx, &[x]) - base, penalty, "SCX={} X={}", scx, x);
+            }
+        }
+    }
+
+    #[test]
+    fn sprite_fetches_add_up() {
+        let base = mode3_len(0, &[]);
+        // Left of the screen, sprites are fetched at the first pixel
+        assert_eq!(mode3_len(0, &[0]) - base, 11);
+        // The second sprite doesn't wait for the BG fetcher again
+        assert_eq!(mode3_len(0, &[8, 8]) - base, 17);
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use crate::gameboy::GameBoy;
+
+// Snapshot stored as the difference to the snapshot taken after it.
+struct Delta {
+    // Length of the snapshot
+    len: usize,
+    // Snapshot XOR the next snapshot, deflate compressed
+    data: Vec<u8>,
+}
+
+// Rewind history of recent save states.
+//
+// Only the latest snapshot is kept whole. Each older one is stored as a
+// compressed delta against its successor, so stepping back undoes one delta
+// at a time and the oldest snapshots can be dropped to stay within the
+// memory budget.
+pub struct Rewind {
+    // Latest snapshot
+    current: Option<Vec<u8>>,
+    // Older snapshots, oldest first
+    deltas: VecDeque<Delta>,
+    // Memory budget in bytes
+    budget: usize,
+    // Memory used by the current snapshot and the deltas
+    used: usize,
+    // Frames between snapshots
+    interval: u32,
+    // Frames run since the current snapshot was taken or loaded
+    frames: u32,
+    // Frames left to show the loaded snapshot while rewinding
+    hold: u32,
+}
+
+impl Rewind {
+    // Creates a rewind history taking a snapshot every `interval` frames and
+    // keeping at most `budget` bytes of snapshots.
+    pub fn new(budget: usize, interval: u32) -> Self {
+        Rewind {
+            current: None,
+            deltas: VecDeque::new(),
+            budget,
+            used: 0,
+            interval: interval.max(1),
+            frames: 0,
+            hold: 0,
+        }
+    }
+
+    // Returns the number of snapshots in the history.
+    pub fn len(&self) -> usize {
+        self.deltas.len() + usize::from(self.current.is_some())
+    }
+
+    // Returns true if there are no snapshots.
+    pub fn is_empty(&self) -> bool {
+        self.current.is_none()
+    }
+
+    // Returns the memory used by snapshots in bytes.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    // Discards all snapshots.
+    pub fn clear(&mut self) {
+        self.current = None;
+        self.deltas.clear();
+        self.used = 0;
+        self.frames = 0;
+        self.hold = 0;
+    }
+
+    // Records a frame of emulation. Takes a snapshot every `interval` frames.
+    pub fn record(&mut self, gameboy: &GameBoy) {
+        self.hold = 0;
+        self.frames += 1;
+        if self.frames < self.interval && self.current.is_some() {
+            return;
+        }
+        self.frames = 0;
+
+        let snapshot = gameboy.save_state();
+        self.used += snapshot.len();
+
+        if let Some(prev) = self.current.take() {
+            let data = compress(&xor(&prev, &snapshot));
+            self.used = self.used - prev.len() + data.len();
+            self.deltas.push_back(Delta {
+                len: prev.len(),
+                data,
+            });
+        }
+        self.current = Some(snapshot);
+
+        // Drop the oldest snapshots to stay within the budget
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.data.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Rewinds by one frame. Called every frame while rewinding, it steps back
+    // one snapshot every `interval` frames so the game plays backwards at
+    // normal speed. Returns false once the oldest snapshot is reached.
+    pub fn rewind_frame(&mut self, gameboy: &mut GameBoy) -> bool {
+        if self.hold > 0 {
+            self.hold -= 1;
+            return true;
+        }
+
+        // Go back to the current snapshot if the game has run past it,
+        // otherwise to the one before it
+        if self.frames == 0 && !self.step_back() {
+            return false;
+        }
+
+        let current = match &self.current {
+            Some(current) => current,
+            None => return false,
+        };
+        self.frames = 0;
+        self.hold = self.interval - 1;
+        gameboy.load_state(current).is_ok()
+    }
+
+    // Replaces the current snapshot with the one before it. Returns false if
+    // there is none.
+    fn step_back(&mut self) -> bool {
+        let (current, delta) = match (&self.current, self.deltas.pop_back()) {
+            (Some(current), Some(delta)) => (current, delta),
+            _ => return false,
+        };
+
+        let mut prev = xor(current, &decompress(&delta.data));
+        prev.truncate(delta.len);
+
+        self.used = self.used - current.len() - delta.data.len() + prev.len();
+        self.current = Some(prev);
+        true
+    }
+}
+
+// Returns the bytewise XOR of two buffers, the shorter one padded with zeros.
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut out = long.to_vec();
+
+    for (out, val) in out.iter_mut().zip(short) {
+        *out ^= val;
+    }
+    out
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data).expect("Failed to compress snapshot");
+    encoder.finish().expect("Failed to compress snapshot")
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    DeflateDecoder::new(data).read_to_end(&mut out).expect("Failed to decompress snapshot");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::tests::program_rom;
+    use crate::gameboy::Config;
+
+    fn gameboy() -> GameBoy {
+        let program = [
+            0x21, 0x00, 0xc0, // ld hl,$c000
+            0x34, // inc (hl)
+            0x18, 0xfd, // jr -3
+        ];
+        GameBoy::from_rom(program_rom(&program), Config::default()).unwrap()
+    }
+
+    // Runs and records frames. Returns the state after each frame.
+    fn record_frames(rewind: &mut Rewind, gameboy: &mut GameBoy, frames: usize) -> Vec<Vec<u8>> {
+        (0..frames)
+            .map(|_| {
+                gameboy.run_frame();
+                rewind.record(gameboy);
+                gameboy.save_state()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn xor_delta_restores_previous_snapshot() {
+        let current = [1, 2, 3, 4, 5, 6];
+        for prev in [&[1, 2, 7, 4][..], &[9, 2, 3, 4, 5, 6, 7, 8][..]] {
+            let delta = compress(&xor(prev, &current));
+            let mut restored = xor(&current, &decompress(&delta));
+            restored.truncate(prev.len());
+            assert_eq!(restored, prev);
+        }
+    }
+
+    #[test]
+    fn snapshot_every_interval() {
+        let mut gameboy = gameboy();
+        let mut rewind = Rewind::new(usize::MAX, 3);
+        assert!(rewind.is_empty());
+
+        // The first frame is always recorded
+        record_frames(&mut rewind, &mut gameboy, 7);
+        assert_eq!(rewind.len(), 3);
+
+        rewind.clear();
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.used(), 0);
+    }
+
+    #[test]
+    fn rewind_restores_recorded_states() {
+        let mut gameboy = gameboy();
+        let mut rewind = Rewind::new(usize::MAX, 1);
+        let states = record_frames(&mut rewind, &mut gameboy, 5);
+
+        // The latest snapshot is the current state, so rewinding starts with
+        // the one before it
+        for state in states[..4].iter().rev() {
+            assert!(rewind.rewind_frame(&mut gameboy));
+            assert_eq!(&gameboy.save_state(), state);
+        }
+        assert!(!rewind.rewind_frame(&mut gameboy));
+        assert_eq!(&gameboy.save_state(), &states[0]);
+        assert_eq!(rewind.len(), 1);
+        assert_eq!(rewind.used(), states[0].len());
+    }
+
+    #[test]
+    fn rewind_holds_snapshots_for_interval() {
+        let mut gameboy = gameboy();
+        let mut rewind = Rewind::new(usize::MAX, 2);
+        // Snapshots are taken after frames 1 and 3
+        let states = record_frames(&mut rewind, &mut gameboy, 4);
+
+        // The game ran past the latest snapshot, so that one is loaded first
+        assert!(rewind.rewind_frame(&mut gameboy));
+        assert_eq!(gameboy.save_state(), states[2]);
+        gameboy.run_frame();
+        assert!(rewind.rewind_frame(&mut gameboy));
+        assert!(rewind.rewind_frame(&mut gameboy));
+        assert_eq!(gameboy.save_state(), states[0]);
+        assert!(rewind.rewind_frame(&mut gameboy));
+        assert!(!rewind.rewind_frame(&mut gameboy));
+    }
+
+    #[test]
+    fn oldest_snapshots_are_dropped_over_budget() {
+        let mut rewind = Rewind::new(usize::MAX, 1);
+        let states = record_frames(&mut rewind, &mut gameboy(), 6);
+        let state_len = states[0].len();
+        let deltas_len = rewind.used() - state_len;
+
+        // Room for about half of the deltas
+        let budget = state_len + deltas_len / 2;
+        let mut gameboy = gameboy();
+        let mut rewind = Rewind::new(budget, 1);
+        assert_eq!(record_frames(&mut rewind, &mut gameboy, 6), states);
+        assert!(rewind.used() <= budget);
+        let kept = rewind.len();
+        assert!(kept > 1 && kept < 6);
+
+        // The newest snapshots are left
+        for state in states[6 - kept..5].iter().rev() {
+            assert!(rewind.rewind_frame(&mut gameboy));
+            assert_eq!(&gameboy.save_state(), state);
+        }
+        assert!(!rewind.rewind_frame(&mut gameboy));
+    }
+}
@@ -0,0 +1,377 @@
+use std::time::Duration;
+
+use crate::bus::Bus;
+use crate::link::{Link, LinkMessage};
+use crate::state::{StateError, StateReader, StateWriter};
+
+// Clocks per bit with the internal clock (8192 Hz).
+const CLOCKS_PER_BIT: u16 = 512;
+// Clocks the clock master waits for the partner's byte before shifting in
+// 1s (one second).
+const REPLY_TIMEOUT: u32 = 4194304;
+// Clocks between sync messages to the partner (one frame).
+const SYNC_INTERVAL: u32 = 456 * 154;
+// Sync messages the partner may lag behind before we wait for it.
+const MAX_LEAD: i32 = 2;
+// How long to wait for a lagging partner before running on without it.
+const LINK_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct Serial {
+    /// Serial transfer data
+    sb: u8,
+    /// Serial transfer control
+    sc: u8,
+    /// Elapsed clocks in current bit
+    counter: u16,
+    /// Remaining bits of current transfer
+    bits_left: u8,
+    /// Byte being shifted out
+    out: u8,
+    /// Called with every byte sent
+    callback: Option<Box<dyn FnMut(u8)>>,
+    /// Link cable partner
+    link: Option<Box<dyn Link>>,
+    /// Byte received from a partner which started a transfer at the same time
+    collision: Option<u8>,
+    /// Byte shifted back by the partner for our internal clock transfer
+    reply: Option<u8>,
+    /// Clocks waited for the partner's byte after shifting out ours
+    reply_wait: u32,
+    /// Clocks since the last sync message
+    sync_counter: u32,
+    /// Sync messages sent to and received from the partner
+    syncs_sent: u32,
+    syncs_received: u32,
+    /// Interrupt request
+    pub irq: bool,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    /// Creates a new `Serial`.
+    pub fn new() -> Self {
+        Serial {
+            sb: 0,
+            sc: 0,
+            counter: 0,
+            bits_left: 0,
+            out: 0,
+            callback: None,
+            link: None,
+            collision: None,
+            reply: None,
+            reply_wait: 0,
+            sync_counter: 0,
+            syncs_sent: 0,
+            syncs_received: 0,
+            irq: false,
+        }
+    }
+
+    /// Sets a callback which receives every transferred byte.
+    pub fn set_callback(&mut self, callback: Box<dyn FnMut(u8)>) {
+        self.callback = Some(callback);
+    }
+
+    /// Connects a link cable partner.
+    pub fn connect(&mut self, link: Box<dyn Link>) {
+        self.link = Some(link);
+    }
+
+    /// Serializes the transfer state. The link cable connection is kept.
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u16(self.counter);
+        w.u8(self.bits_left);
+        w.u8(self.out);
+        w.bool(self.collision.is_some());
+        w.u8(self.collision.unwrap_or(0));
+        w.bool(self.irq);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()?;
+        self.counter = r.u16()?;
+        self.bits_left = r.u8()?;
+        self.out = r.u8()?;
+        let collision = r.bool()?;
+        let val = r.u8()?;
+        self.collision = if collision { Some(val) } else { None };
+        self.irq = r.bool()?;
+        self.reply = None;
+        self.reply_wait = 0;
+        Ok(())
+    }
+
+    /// Sends a sync message to the partner once per frame. Called with
+    /// clocks at normal speed, so both sides count frames alike in double
+    /// speed mode.
+    pub fn sync(&mut self, clocks: u8) {
+        let link = match &mut self.link {
+            Some(link) => link,
+            None => return,
+        };
+
+        self.sync_counter += clocks as u32;
+        if self.sync_counter < SYNC_INTERVAL {
+            return;
+        }
+        self.sync_counter -= SYNC_INTERVAL;
+        link.send(LinkMessage::Sync);
+        self.syncs_sent = self.syncs_sent.wrapping_add(1);
+    }
+
+    /// Keeps emulation in step with the link cable partner by waiting while
+    /// more than `MAX_LEAD` frames ahead of it. Called by the frontend
+    /// between frames, so a slow partner never stalls a frame halfway.
+    pub fn wait_for_partner(&mut self) {
+        while self.syncs_sent.wrapping_sub(self.syncs_received) as i32 > MAX_LEAD {
+            match self.link.as_mut().and_then(|link| link.recv_timeout(LINK_TIMEOUT)) {
+                Some(msg) => self.handle_message(msg),
+                // The partner is paused or gone
+                None => self.syncs_received = self.syncs_sent,
+            }
+        }
+    }
+
+    fn start_transfer(&mut self) {
+        self.counter = 0;
+        self.bits_left = 8;
+        self.out = self.sb;
+        self.reply = None;
+        self.reply_wait = 0;
+
+        // The partner gets our byte right away, so it can answer while our
+        // transfer is in progress
+        if let Some(link) = &mut self.link {
+            link.send(LinkMessage::Data(self.out));
+        }
+    }
+
+    /// Returns the byte shifted in by the partner for a transfer driven by
+    /// our clock, once it has arrived.
+    fn receive(&mut self) -> Option<u8> {
+        // Both sides started a transfer, each takes the other's byte
+        if let Some(val) = self.collision.take() {
+            return Some(val);
+        }
+        if let Some(val) = self.reply.take() {
+            return Some(val);
+        }
+
+        // A partner which doesn't answer behaves like an unplugged cable
+        if self.reply_wait >= REPLY_TIMEOUT {
+            return Some(0xff);
+        }
+        None
+    }
+
+    /// Handles messages received from the partner.
+    fn poll_link(&mut self) {
+        while let Some(msg) = self.link.as_mut().and_then(|link| link.try_recv()) {
+            self.handle_message(msg);
+        }
+    }
+
+    fn handle_message(&mut self, msg: LinkMessage) {
+        let val = match msg {
+            LinkMessage::Data(val) => val,
+            // Kept until our transfer has shifted out all bits
+            LinkMessage::Reply(val) => {
+                if self.sc == 0x81 {
+                    self.reply = Some(val);
+                }
+                return;
+            }
+            LinkMessage::Sync => {
+                self.syncs_received = self.syncs_received.wrapping_add(1);
+                return;
+            }
+        };
+
+        // Both sides started a transfer with the internal clock
+        if self.sc == 0x81 {
+            self.collision = Some(val);
+            return;
+        }
+
+        if let Some(link) = &mut self.link {
+            link.send(LinkMessage::Reply(self.sb));
+        }
+
+        // Transfer waiting for the external clock
+        if self.sc == 0x80 {
+            self.out = self.sb;
+            self.sb = val;
+            self.finish_transfer();
+        }
+    }
+
+    fn finish_transfer(&mut self) {
+        self.sc &= 0x7f;
+        self.irq = true;
+
+        if let Some(callback) = &mut self.callback {
+            callback(self.out);
+        }
+    }
+}
+
+impl Bus for Serial {
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // SB
+            0xff01 => self.sb = val,
+            // SC
+            0xff02 => {
+                self.sc = val & 0x81;
+                if self.sc == 0x81 {
+                    self.start_transfer();
+                }
+            }
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // SB
+            0xff01 => self.sb,
+            // SC
+            0xff02 => self.sc | 0x7e,
+            _ => unreachable!("Unexpected address: 0x{:04x}", addr),
+        }
+    }
+
+    fn update(&mut self, tick: u8) {
+        if self.link.is_some() {
+            self.poll_link();
+        }
+
+        // Transfers using the external clock are driven by the partner
+        if self.sc != 0x81 {
+            return;
+        }
+
+        if self.bits_left > 0 {
+            self.counter += tick as u16;
+
+            while self.counter >= CLOCKS_PER_BIT && self.bits_left > 0 {
+                self.counter -= CLOCKS_PER_BIT;
+                self.bits_left -= 1;
+
+                if self.link.is_none() {
+                    // Nothing is connected, so 1s are shifted in
+                    self.sb = (self.sb << 1) | 0x01;
+                }
+            }
+
+            if self.bits_left > 0 {
+                return;
+            }
+            if self.link.is_none() {
+                self.finish_transfer();
+                return;
+            }
+        } else {
+            self.reply_wait += tick as u32;
+        }
+
+        // All bits are shifted out, the transfer ends when the partner's
+        // byte arrives
+        if let Some(val) = self.receive() {
+            self.sb = val;
+            self.finish_transfer();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    use super::*;
+
+    // Partner which answers every byte right away, or never.
+    struct MockLink {
+        reply: Option<u8>,
+        inbox: VecDeque<LinkMessage>,
+    }
+
+    impl Link for MockLink {
+        fn send(&mut self, msg: LinkMessage) {
+            if let (LinkMessage::Data(_), Some(val)) = (msg, self.reply) {
+                self.inbox.push_back(LinkMessage::Reply(val));
+            }
+        }
+
+        fn try_recv(&mut self) -> Option<LinkMessage> {
+            self.inbox.pop_front()
+        }
+
+        fn recv_timeout(&mut self, _timeout: Duration) -> Option<LinkMessage> {
+            self.inbox.pop_front()
+        }
+    }
+
+    fn linked_serial(reply: Option<u8>) -> Serial {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(MockLink {
+            reply,
+            inbox: VecDeque::new(),
+        }));
+        serial
+    }
+
+    // Runs until the transfer ends. Returns the clocks it took.
+    fn run_transfer(serial: &mut Serial) -> u32 {
+        let mut clocks = 0;
+        while serial.read(0xff02) & 0x80 > 0 && clocks <= REPLY_TIMEOUT + 8192 {
+            serial.update(4);
+            clocks += 4;
+        }
+        clocks
+    }
+
+    #[test]
+    fn reply_during_transfer_is_received() {
+        let mut serial = linked_serial(Some(0x42));
+        serial.write(0xff01, 0x12);
+        serial.write(0xff02, 0x81);
+
+        assert_eq!(run_transfer(&mut serial), 8 * CLOCKS_PER_BIT as u32);
+        assert_eq!(serial.read(0xff01), 0x42);
+        assert!(serial.irq);
+    }
+
+    #[test]
+    fn unanswered_transfer_times_out() {
+        let mut serial = linked_serial(None);
+        serial.write(0xff01, 0x12);
+        serial.write(0xff02, 0x81);
+
+        assert!(run_transfer(&mut serial) >= REPLY_TIMEOUT);
+        assert_eq!(serial.read(0xff01), 0xff);
+        assert!(serial.irq);
+    }
+
+    #[test]
+    fn external_clock_transfer_takes_partner_byte() {
+        let mut serial = linked_serial(None);
+        serial.write(0xff01, 0x34);
+        serial.write(0xff02, 0x80);
+        serial.handle_message(LinkMessage::Data(0x56));
+
+        assert_eq!(serial.read(0xff01), 0x56);
+        assert_eq!(serial.read(0xff02) & 0x80, 0);
+        assert!(serial.irq);
+    }
+}
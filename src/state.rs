@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::gameboy::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Identifies a save state file.
+const MAGIC: &[u8; 8] = b"GBDSTATE";
+// Current format version. Changing the layout of a section requires a new
+// version and a migration from the previous one.
+pub const VERSION: u16 = 1;
+
+// Thumbnail size, half the screen in each direction.
+pub const THUMBNAIL_WIDTH: usize = SCREEN_WIDTH / 2;
+pub const THUMBNAIL_HEIGHT: usize = SCREEN_HEIGHT / 2;
+
+// Converts the sections of one version to the next one.
+type Migration = fn(&mut Sections) -> Result<(), StateError>;
+
+// Migrations from version N to N + 1, at index N - 1.
+const MIGRATIONS: [Migration; VERSION as usize - 1] = [];
+
+// Error loading a save state.
+#[derive(Debug)]
+pub enum StateError {
+    // Not a save state file
+    BadMagic,
+    // Written by a newer version
+    UnsupportedVersion(u16),
+    // Saved with another ROM, whose title is given
+    RomMismatch(String),
+    MissingSection([u8; 4]),
+    // Data ends in the middle of a value
+    Truncated,
+    // State doesn't fit the loaded cartridge or configuration
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported save state version {} (newest supported is {})",
+                version, VERSION
+            ),
+            StateError::RomMismatch(title) => write!(f, "Save state is for another ROM: {}", title),
+            StateError::MissingSection(tag) => write!(
+                f,
+                "Save state has no {} section",
+                String::from_utf8_lossy(tag).trim_end()
+            ),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Invalid(msg) => write!(f, "Invalid save state: {}", msg),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+// Header of a save state, which can be read without loading the state.
+#[derive(Clone, Debug)]
+pub struct StateHeader {
+    pub version: u16,
+    // Cartridge title and checksums identifying the ROM
+    pub title: String,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    // Frame at the time of saving, THUMBNAIL_WIDTH x THUMBNAIL_HEIGHT RGB
+    pub thumbnail: Vec<u8>,
+}
+
+// Serializes values in little endian.
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub(crate) fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub(crate) fn bool(&mut self, val: bool) {
+        self.data.push(u8::from(val));
+    }
+
+    pub(crate) fn u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // Writes bytes of a size known to the reader.
+    pub(crate) fn bytes(&mut self, val: &[u8]) {
+        self.data.extend_from_slice(val);
+    }
+
+    // Writes bytes preceded by their length.
+    pub(crate) fn blob(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.bytes(val);
+    }
+
+    // Writes a section with the given tag.
+    pub(crate) fn section(&mut self, tag: &[u8; 4], f: impl FnOnce(&mut StateWriter)) {
+        let mut section = StateWriter::new();
+        f(&mut section);
+
+        self.bytes(tag);
+        self.blob(&section.data);
+    }
+}
+
+// Deserializes values written by `StateWriter`.
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+
+        let (val, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(val)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? > 0)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // Reads bytes into a buffer of a known size.
+    pub(crate) fn bytes(&mut self, buf: &mut [u8]) -> Result<(), StateError> {
+        buf.copy_from_slice(self.take(buf.len())?);
+        Ok(())
+    }
+
+    // Reads bytes preceded by their length.
+    pub(crate) fn blob(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+// Sections of a save state by tag.
+pub(crate) struct Sections {
+    sections: HashMap<[u8; 4], Vec<u8>>,
+}
+
+impl Sections {
+    // Returns a reader for the section with the given tag.
+    pub(crate) fn reader(&self, tag: &[u8; 4]) -> Result<StateReader<'_>, StateError> {
+        match self.sections.get(tag) {
+            Some(data) => Ok(StateReader::new(data)),
+            None => Err(StateError::MissingSection(*tag)),
+        }
+    }
+}
+
+// Reads the header of a save state.
+pub fn read_header(data: &[u8]) -> Result<StateHeader, StateError> {
+    parse_header(&mut StateReader::new(data), VERSION)
+}
+
+// Reads the header of a save state written by a version up to `newest`.
+fn parse_header(r: &mut StateReader, newest: u16) -> Result<StateHeader, StateError> {
+    if r.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(StateError::BadMagic);
+    }
+
+    let version = r.u16()?;
+    if version == 0 || version > newest {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+
+    Ok(StateHeader {
+        version,
+        header_checksum: r.u8()?,
+        global_checksum: r.u16()?,
+        title: String::from_utf8_lossy(r.blob()?).into_owned(),
+        thumbnail: r.blob()?.to_vec(),
+    })
+}
+
+// Serializes the state of the whole machine.
+pub(crate) fn save(cpu: &CPU) -> Vec<u8> {
+    let cartridge = &cpu.mmu.cartridge;
+    let mut w = StateWriter::new();
+
+    w.bytes(MAGIC);
+    w.u16(VERSION);
+    w.u8(cartridge.header_checksum);
+    w.u16(cartridge.global_checksum());
+    w.blob(cartridge.title_to_string().as_bytes());
+    w.blob(&thumbnail(cpu.mmu.ppu.frame_buffer()));
+
+    cpu.save_state(&mut w);
+    w.data
+}
+
+// Restores the state of the whole machine. On error, the machine may be
+// partially restored.
+pub(crate) fn load(cpu: &mut CPU, data: &[u8]) -> Result<(), StateError> {
+    load_migrated(cpu, data, VERSION, &MIGRATIONS)
+}
+
+// Restores a state written by a version up to `newest`, converting its
+// sections to the layout of `newest` with `migrations`.
+fn load_migrated(
+    cpu: &mut CPU,
+    data: &[u8],
+    newest: u16,
+    migrations: &[Migration],
+) -> Result<(), StateError> {
+    let mut r = StateReader::new(data);
+    let header = parse_header(&mut r, newest)?;
+
+    let cartridge = &cpu.mmu.cartridge;
+    if header.header_checksum != cartridge.header_checksum
+        || header.global_checksum != cartridge.global_checksum()
+    {
+        return Err(StateError::RomMismatch(header.title));
+    }
+
+    let mut sections = Sections {
+        sections: HashMap::new(),
+    };
+    while !r.is_empty() {
+        let mut tag = [0; 4];
+        r.bytes(&mut tag)?;
+        sections.sections.insert(tag, r.blob()?.to_vec());
+    }
+
+    for migrate in &migrations[header.version as usize - 1..] {
+        migrate(&mut sections)?;
+    }
+
+    cpu.load_state(&sections)
+}
+
+// Scales the frame buffer down to a thumbnail by averaging 2x2 pixels.
+fn thumbnail(frame_buffer: &[u8]) -> Vec<u8> {
+    let mut thumbnail = Vec::with_capacity(THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 3);
+
+    for y in 0..THUMBNAIL_HEIGHT {
+        for x in 0..THUMBNAIL_WIDTH {
+            for c in 0..3 {
+                let pixel = |dx: usize, dy: usize| {
+                    frame_buffer[((y * 2 + dy) * SCREEN_WIDTH + x * 2 + dx) * 3 + c] as u16
+                };
+                let sum = pixel(0, 0) + pixel(1, 0) + pixel(0, 1) + pixel(1, 1);
+                thumbnail.push((sum / 4) as u8);
+            }
+        }
+    }
+
+    thumbnail
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::gameboy::tests::program_rom;
+    use crate::gameboy::{Config, GameBoy};
+
+    // Serializes a component with `save` and restores it with `load`.
+    pub(crate) fn reload(
+        save: impl FnOnce(&mut StateWriter),
+        load: impl FnOnce(&mut StateReader) -> Result<(), StateError>,
+    ) -> Result<(), StateError> {
+        let mut w = StateWriter::new();
+        save(&mut w);
+        load(&mut StateReader::new(&w.data))
+    }
+
+    fn gameboy() -> GameBoy {
+        let program = [
+            0x3e, 0x0a, // ld a,$0a
+            0xea, 0x00, 0x00, // ld ($0000),a
+            0x21, 0x00, 0xa0, // ld hl,$a000
+            0x34, // inc (hl)
+            0x0c, // inc c
+            0x18, 0xfc, // jr -4
+        ];
+        GameBoy::from_rom(program_rom(&program), Config::default()).unwrap()
+    }
+
+    // Splits a save state into its header and sections.
+    fn split(data: &[u8]) -> (Vec<u8>, Sections) {
+        let mut r = StateReader::new(data);
+        parse_header(&mut r, VERSION).unwrap();
+        let header = data[..data.len() - r.data.len()].to_vec();
+
+        let mut sections = Sections {
+            sections: HashMap::new(),
+        };
+        while !r.is_empty() {
+            let mut tag = [0; 4];
+            r.bytes(&mut tag).unwrap();
+            sections.sections.insert(tag, r.blob().unwrap().to_vec());
+        }
+        (header, sections)
+    }
+
+    // Writes a save state with the layout of an older version, where the
+    // timer section was named TIME (changed in version 2) and the joypad
+    // section had a padding byte (removed in version 3).
+    fn old_state(data: &[u8], version: u16) -> Vec<u8> {
+        let (mut header, sections) = split(data);
+        header[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&version.to_le_bytes());
+
+        let mut w = StateWriter::new();
+        w.bytes(&header);
+        for (tag, mut section) in sections.sections {
+            let tag = match &tag {
+                b"TIMR" if version < 2 => *b"TIME",
+                _ => tag,
+            };
+            if &tag == b"JOYP" {
+                section.push(0);
+            }
+            w.section(&tag, |w| w.bytes(&section));
+        }
+        w.data
+    }
+
+    fn rename_timer(sections: &mut Sections) -> Result<(), StateError> {
+        let timer = sections
+            .sections
+            .remove(b"TIME")
+            .ok_or(StateError::MissingSection(*b"TIME"))?;
+        sections.sections.insert(*b"TIMR", timer);
+        Ok(())
+    }
+
+    fn remove_joypad_padding(sections: &mut Sections) -> Result<(), StateError> {
+        let joypad = sections
+            .sections
+            .get_mut(b"JOYP")
+            .ok_or(StateError::MissingSection(*b"JOYP"))?;
+        joypad.pop();
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut gameboy = gameboy();
+        gameboy.run_frame();
+        let state = gameboy.save_state();
+        gameboy.run_frame();
+        let next_state = gameboy.save_state();
+
+        // Running on from a loaded state repeats the same frame
+        gameboy.run_frame();
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.save_state(), state);
+        gameboy.run_frame();
+        assert_eq!(gameboy.save_state(), next_state);
+    }
+
+    #[test]
+    fn old_versions_are_migrated() {
+        let mut gameboy = gameboy();
+        gameboy.run_frame();
+        let state = gameboy.save_state();
+        let migrations: [Migration; 2] = [rename_timer, remove_joypad_padding];
+
+        for version in 1..=2 {
+            let mut gameboy = self::gameboy();
+            load_migrated(&mut gameboy.cpu, &old_state(&state, version), 3, &migrations).unwrap();
+            assert_eq!(gameboy.save_state(), state, "version {}", version);
+        }
+
+        // Without the migrations, the old layout doesn't load
+        let result = load_migrated(&mut gameboy.cpu, &old_state(&state, 1), 1, &[]);
+        assert!(matches!(result, Err(StateError::MissingSection(tag)) if &tag == b"TIMR"));
+    }
+
+    #[test]
+    fn bad_states_leave_machine_unchanged() {
+        let mut gameboy = gameboy();
+        gameboy.run_frame();
+        let state = gameboy.save_state();
+        gameboy.run_frame();
+        let current = gameboy.save_state();
+
+        let result = gameboy.load_state(&state[..state.len() - 1]);
+        assert!(matches!(result, Err(StateError::Truncated)));
+
+        let mut newer = state.clone();
+        newer[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let result = gameboy.load_state(&newer);
+        assert!(matches!(result, Err(StateError::UnsupportedVersion(v)) if v == VERSION + 1));
+
+        let result = gameboy.load_state(b"GBDSTAT");
+        assert!(matches!(result, Err(StateError::BadMagic)));
+
+        assert_eq!(gameboy.save_state(), current);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::bus::Bus;
+use crate::state::{StateError, StateReader, StateWriter};
 
 pub struct Timer {
     /// Timer counter
@@ -24,6 +25,28 @@ impl Timer {
             irq: false,
         }
     }
+
+    /// Sets the internal counter (DIV is its upper 8 bits).
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u16(self.counter);
+        w.bool(self.irq);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()?;
+        self.counter = r.u16()?;
+        self.irq = r.bool()?;
+        Ok(())
+    }
 }
 
 impl Bus for Timer {
@@ -50,7 +73,7 @@ impl Bus for Timer {
             // TMA
             0xff06 => self.tma,
             // TAC
-            0xff07 => self.tac,
+            0xff07 => self.tac | 0xf8,
             _ => unreachable!("Unexpected address: 0x{:04x}", addr),
         }
     }